@@ -0,0 +1,49 @@
+// Compares node and data reads across the storage backends.
+//
+//     cargo run --release --example storage_bench
+
+extern crate dat;
+extern crate rand;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::time::Instant;
+
+use dat::core::Hypercore;
+use dat::core::storage::{Storage, FileStorage, CachedStorage, MmapStorage};
+
+use common::temp_dir;
+
+const BLOCKS: u64 = 4096;
+const ROUNDS: u64 = 16;
+
+fn bench<T: Storage>(name: &str, storage: T) {
+    let mut feed = Hypercore::new(storage).unwrap();
+    let data = vec![1u8; 1024];
+
+    let start = Instant::now();
+    for _ in 0..BLOCKS {
+        feed.append(data.clone()).unwrap();
+    }
+    let append = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for i in 0..BLOCKS {
+            feed.get(i).unwrap();
+        }
+    }
+    let get = start.elapsed();
+
+    println!("{:<8} append: {:>6} ms   get: {:>6} ms",
+             name,
+             append.as_secs() * 1000 + append.subsec_nanos() as u64 / 1_000_000,
+             get.as_secs() * 1000 + get.subsec_nanos() as u64 / 1_000_000);
+}
+
+fn main() {
+    bench("file", FileStorage::new(&temp_dir("dat-bench-file")).unwrap());
+    bench("cached", CachedStorage::new(FileStorage::new(&temp_dir("dat-bench-cached")).unwrap()));
+    bench("mmap", MmapStorage::new(&temp_dir("dat-bench-mmap")).unwrap());
+}
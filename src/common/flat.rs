@@ -130,4 +130,140 @@ pub fn is_left(idx: u64) -> bool {
 
 pub fn is_right(idx: u64) -> bool {
     !is_left(idx)
+}
+
+// Walks the tree from node to node without recomputing depths. `factor` is
+// twice the number of leaves under the current node.
+pub struct TreeIterator {
+    index:      u64,
+    offset:     u64,
+    factor:     u64,
+}
+
+impl TreeIterator {
+    pub fn new(idx: u64) -> TreeIterator {
+        let mut iter = TreeIterator { index: 0, offset: 0, factor: 2 };
+        iter.seek(idx);
+        iter
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn factor(&self) -> u64 {
+        self.factor
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.factor.trailing_zeros() as u64 - 1
+    }
+
+    pub fn seek(&mut self, idx: u64) {
+        let depth = depth(idx);
+        self.index = idx;
+        self.offset = offset_with_depth(idx, depth);
+        self.factor = 2 << depth;
+    }
+
+    pub fn is_left(&self) -> bool {
+        self.offset & 1 == 0
+    }
+
+    pub fn is_right(&self) -> bool {
+        !self.is_left()
+    }
+
+    // Stays put at the left edge of the tree.
+    pub fn prev_node(&mut self) -> u64 {
+        if self.offset == 0 {
+            return self.index;
+        }
+        self.offset -= 1;
+        self.index -= self.factor;
+        self.index
+    }
+
+    pub fn next_node(&mut self) -> u64 {
+        self.offset += 1;
+        self.index += self.factor;
+        self.index
+    }
+
+    pub fn sibling(&mut self) -> u64 {
+        if self.is_left() { self.next_node() } else { self.prev_node() }
+    }
+
+    pub fn parent(&mut self) -> u64 {
+        if self.is_right() {
+            self.index -= self.factor / 2;
+        } else {
+            self.index += self.factor / 2;
+        }
+        self.offset /= 2;
+        self.factor *= 2;
+        self.index
+    }
+
+    // Leaves stay where they are.
+    pub fn left_child(&mut self) -> u64 {
+        if self.factor == 2 {
+            return self.index;
+        }
+        self.factor /= 2;
+        self.index -= self.factor / 2;
+        self.offset *= 2;
+        self.index
+    }
+
+    pub fn right_child(&mut self) -> u64 {
+        if self.factor == 2 {
+            return self.index;
+        }
+        self.factor /= 2;
+        self.index += self.factor / 2;
+        self.offset = 2 * self.offset + 1;
+        self.index
+    }
+
+    pub fn left_span(&mut self) -> u64 {
+        self.index = self.index + 1 - self.factor / 2;
+        self.offset = self.index / 2;
+        self.factor = 2;
+        self.index
+    }
+
+    pub fn right_span(&mut self) -> u64 {
+        self.index = self.index + self.factor / 2 - 1;
+        self.offset = self.index / 2;
+        self.factor = 2;
+        self.index
+    }
+
+    // The first leaf after the current subtree.
+    pub fn next_tree(&mut self) -> u64 {
+        self.index = self.index + self.factor / 2 + 1;
+        self.offset = self.index / 2;
+        self.factor = 2;
+        self.index
+    }
+
+    // From a leaf, climbs to the biggest subtree starting there that is
+    // complete below `idx`, so seeking to 0 and alternating this with
+    // `next_tree` visits `full_roots(idx)`. False once we're past `idx`.
+    pub fn full_root(&mut self, idx: u64) -> bool {
+        if idx <= self.index || self.index & 1 != 0 {
+            return false;
+        }
+        while idx > self.index + self.factor + self.factor / 2 {
+            self.index += self.factor / 2;
+            self.factor *= 2;
+            self.offset /= 2;
+        }
+        true
+    }
 }
\ No newline at end of file
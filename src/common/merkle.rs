@@ -1,7 +1,48 @@
 use digest::{Digest, VariableOutput};
+use blake2::Blake2b;
+use sha2::Sha256;
 
 use common::flat;
 
+// The hash a tree is built with. Its name is written into the tree file's
+// header, so a feed can't be reopened with a different one.
+pub trait TreeHash {
+    fn name() -> &'static str;
+    fn digest(parts: &[&[u8]]) -> [u8; 32];
+}
+
+impl TreeHash for Blake2b {
+    fn name() -> &'static str {
+        "BLAKE2b"
+    }
+
+    fn digest(parts: &[&[u8]]) -> [u8; 32] {
+        let mut arr = [0u8; 32];
+        let mut hasher: Blake2b = VariableOutput::new(32).unwrap();
+        for part in parts {
+            hasher.input(part);
+        }
+        hasher.variable_result(&mut arr).unwrap();
+        arr
+    }
+}
+
+impl TreeHash for Sha256 {
+    fn name() -> &'static str {
+        "SHA256"
+    }
+
+    fn digest(parts: &[&[u8]]) -> [u8; 32] {
+        let mut arr = [0u8; 32];
+        let mut hasher = Sha256::default();
+        for part in parts {
+            hasher.input(part);
+        }
+        arr.copy_from_slice(&hasher.result());
+        arr
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub index:  u64,
@@ -25,11 +66,8 @@ impl Node {
     }
 
     pub fn with_data<D>(idx: u64, data: Vec<u8>) -> Node
-                        where D: VariableOutput + Digest {
-        let mut arr = [0u8; 32];
-        let mut hasher: D = VariableOutput::new(32).unwrap();
-        hasher.input(&data);
-        hasher.variable_result(&mut arr).unwrap();
+                        where D: TreeHash {
+        let arr = D::digest(&[&data]);
         Node {
             index:      idx,
             parent:     flat::parent(idx),
@@ -40,12 +78,8 @@ impl Node {
     }
 
     pub fn with_nodes<D>(left: &Node, right: &Node) -> Node
-                        where D: VariableOutput + Digest {
-        let mut arr = [0u8; 32];
-        let mut hasher: D = VariableOutput::new(32).unwrap();
-        hasher.input(&left.hash);
-        hasher.input(&right.hash);
-        hasher.variable_result(&mut arr).unwrap();
+                        where D: TreeHash {
+        let arr = D::digest(&[&left.hash, &right.hash]);
         Node {
             index:      left.parent,
             parent:     flat::parent(left.parent),
@@ -83,7 +117,7 @@ impl Tree {
     }
     
     pub fn insert<D>(&mut self, data: Vec<u8>) -> Vec<Node>
-                    where D: VariableOutput + Digest {
+                    where D: TreeHash {
         let mut nodes: Vec<Node> = Vec::new();
         let node = Node::with_data::<D>(self.blocks * 2, data);
         self.blocks += 1;
@@ -2,4 +2,5 @@ pub mod varint;
 pub mod flat;
 pub mod merkle;
 pub mod sparse;
-pub mod pager;
\ No newline at end of file
+pub mod pager;
+pub mod rle;
\ No newline at end of file
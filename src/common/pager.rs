@@ -1,13 +1,31 @@
+use std::io::Result;
+use std::cmp;
+use std::usize;
 use std::collections::{HashMap};
 use std::collections::hash_map::{Iter, Entry};
+use std::sync::Mutex;
 use indexmap::IndexSet;
+use lru_cache::LruCache;
 
 const PAGE_SIZE: usize = 3328;
 
+// Where a paged `Pager` loads pages it doesn't hold from. Dirty pages are
+// never evicted; they go back to storage through `last_updated` as usual.
+pub trait PageSource: Send + Sync {
+    fn pages(&self) -> Result<usize>;
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<usize>;
+}
+
+// `used` only holds clean pages, least recently used first, so the next
+// page to drop is always at its front.
 pub struct Pager {
     map:        HashMap<usize, Vec<u8>>,
     updated:    IndexSet<usize>,
     page_size:  usize,
+    source:     Option<Box<PageSource>>,
+    max_pages:  usize,
+    extent:     usize,
+    used:       Mutex<LruCache<usize, ()>>,
 }
 
 impl Pager {
@@ -16,9 +34,20 @@ impl Pager {
             map:        HashMap::new(),
             updated:    IndexSet::new(),
             page_size:  PAGE_SIZE,
+            source:     None,
+            max_pages:  0,
+            extent:     0,
+            used:       Mutex::new(LruCache::new(usize::MAX)),
         }
     }
 
+    // For a bitfield that has pages to itself rather than sharing them.
+    pub fn with_page_size(page_size: usize) -> Pager {
+        let mut pager = Pager::new();
+        pager.page_size = page_size;
+        pager
+    }
+
     pub fn from_vec(vec: Vec<u8>) -> Pager {
         let mut pager = Pager::new();
         let length = vec.len();
@@ -37,45 +66,137 @@ impl Pager {
         pager
     }
 
+    // Keeps roughly `budget` bytes of pages in memory, loading the rest from
+    // `source` when they're touched and dropping the least recently used
+    // clean ones to make room.
+    pub fn with_source(source: Box<PageSource>, budget: usize) -> Result<Pager> {
+        let mut pager = Pager::new();
+        pager.extent = try!(source.pages());
+        pager.max_pages = cmp::max(1, budget / PAGE_SIZE);
+        pager.source = Some(source);
+        Ok(pager)
+    }
+
+    pub fn is_paged(&self) -> bool {
+        self.source.is_some()
+    }
+
     pub fn get(&self, index: usize) -> Option<&Vec<u8>> {
+        if self.is_paged() {
+            self.used.lock().unwrap().get_mut(&index);
+        }
         self.map.get(&index)
     }
 
-    pub fn set(&mut self, page_num: usize, byte_num: usize, value: u8) -> bool {
+    // Brings a page in from the source if it isn't held already. Returns
+    // false if the source doesn't have it either.
+    pub fn load(&mut self, index: usize) -> Result<bool> {
+        if self.map.contains_key(&index) {
+            return Ok(true);
+        }
+
+        let mut page = vec![0u8; self.page_size];
+        let num_bytes = match self.source {
+            Some(ref source)    => try!(source.read_page(index, &mut page)),
+            None                => 0,
+        };
+
+        if num_bytes == 0 {
+            return Ok(false);
+        }
+
+        self.insert(index, page);
+        Ok(true)
+    }
+
+    pub fn set(&mut self, page_num: usize, byte_num: usize, value: u8) -> Result<bool> {
+        if self.is_paged() {
+            try!(self.load(page_num));
+        }
+
         match self.map.entry(page_num) {
             Entry::Vacant(page) => {
-                if value == 0 { return false; }
+                if value == 0 { return Ok(false); }
                 let mut vec = vec![0u8; self.page_size];
                 vec[byte_num] = value;
                 page.insert(vec);
             },
             Entry::Occupied(entry) => {
                 let mut page = entry.into_mut();
-                if page[byte_num] == value { return false; }
+                if page[byte_num] == value { return Ok(false); }
                 page[byte_num] = value;
             }
         }
         self.updated.insert(page_num);
-        true
+        self.touch(page_num);
+        Ok(true)
+    }
+
+    // Copies page `index` into `buf` whether it's held or not, without
+    // keeping it. Returns false if the page doesn't exist.
+    pub fn read(&self, index: usize, buf: &mut [u8]) -> Result<bool> {
+        if let Some(page) = self.get(index) {
+            buf.copy_from_slice(page);
+            return Ok(true);
+        }
+
+        match self.source {
+            Some(ref source)    => Ok(try!(source.read_page(index, buf)) > 0),
+            None                => Ok(false),
+        }
     }
 
     pub fn insert(&mut self, index: usize, value: Vec<u8>) {
         self.map.insert(index, value);
+        self.touch(index);
     }
 
     pub fn get_page_size(&self) -> usize {
         self.page_size
     }
 
+    // One past the highest page, held or not.
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.extent
     }
 
     pub fn iter(&self) -> Iter<usize, Vec<u8>> {
         self.map.iter()
     }
 
+    // Once written back, a page can be evicted again.
     pub fn last_updated(&mut self) -> Option<usize> {
-        self.updated.pop()
+        let page = self.updated.pop();
+        if let Some(page) = page {
+            if self.is_paged() && self.map.contains_key(&page) {
+                self.used.get_mut().unwrap().insert(page, ());
+            }
+        }
+        page
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.extent = cmp::max(self.extent, index + 1);
+        if !self.is_paged() {
+            return;
+        }
+
+        let used = self.used.get_mut().unwrap();
+        if self.updated.contains(&index) {
+            used.remove(&index);
+        } else {
+            used.insert(index, ());
+        }
+
+        while self.map.len() > self.max_pages {
+            match used.remove_lru() {
+                Some((page, _)) if page != index    => { self.map.remove(&page); },
+                Some(_)                             => {
+                    used.insert(index, ());
+                    break;
+                },
+                None                                => break,
+            }
+        }
     }
 }
\ No newline at end of file
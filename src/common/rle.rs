@@ -0,0 +1,100 @@
+use std::io::{Result, Error, ErrorKind};
+
+use common::varint;
+
+// Shortest run of 0x00 or 0xff bytes worth a header of its own.
+const MIN_RUN: usize = 4;
+
+// Run-length encodes a bitfield the way bitfield-rle does. Every sequence
+// starts with a varint header: `length << 2 | bit << 1 | 1` for `length`
+// bytes all set to `bit`, or `length << 1` followed by `length` raw bytes.
+pub fn encode(bitfield: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut raw_start = 0;
+    let mut i = 0;
+
+    while i < bitfield.len() {
+        let byte = bitfield[i];
+        let mut run = 1;
+        if byte == 0 || byte == 255 {
+            while i + run < bitfield.len() && bitfield[i + run] == byte {
+                run += 1;
+            }
+        }
+
+        if run < MIN_RUN || (byte != 0 && byte != 255) {
+            i += 1;
+            continue;
+        }
+
+        push_raw(&mut result, &bitfield[raw_start..i]);
+        let bit = if byte == 255 { 2 } else { 0 };
+        result.extend_from_slice(&varint::encode(run << 2 | bit | 1));
+        i += run;
+        raw_start = i;
+    }
+
+    push_raw(&mut result, &bitfield[raw_start..]);
+    result
+}
+
+// Headers come off the wire, so anything decoding to more than `max_len`
+// bytes is rejected before it's allocated.
+pub fn decode(buf: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut offset = 0;
+
+    while offset < buf.len() {
+        let (header, num_bytes) = try!(read_header(&buf[offset..]));
+        offset += num_bytes;
+
+        if header & 1 == 1 {
+            let byte = if header & 2 != 0 { 255 } else { 0 };
+            let length = try!(grow(result.len(), header >> 2, max_len));
+            result.resize(length, byte);
+            continue;
+        }
+
+        let length = header >> 1;
+        if length > buf.len() - offset {
+            return Err(Error::new(ErrorKind::Other, "Bitfield is truncated."));
+        }
+        try!(grow(result.len(), length, max_len));
+        result.extend_from_slice(&buf[offset..(offset + length)]);
+        offset += length;
+    }
+
+    Ok(result)
+}
+
+// Bitfields go over the wire with the first block of each byte in its high
+// bit, the other way round from how we store them.
+pub fn swap_bit_order(byte: u8) -> u8 {
+    let mut result = 0;
+    for bit in 0..8 {
+        if byte & (1 << bit) != 0 {
+            result |= 128 >> bit;
+        }
+    }
+    result
+}
+
+fn grow(len: usize, more: usize, max_len: usize) -> Result<usize> {
+    match len.checked_add(more) {
+        Some(length) if length <= max_len   => Ok(length),
+        _                                   => Err(Error::new(ErrorKind::Other, "Bitfield is too large.")),
+    }
+}
+
+fn push_raw(result: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() { return; }
+    result.extend_from_slice(&varint::encode(bytes.len() << 1));
+    result.extend_from_slice(bytes);
+}
+
+fn read_header(buf: &[u8]) -> Result<(usize, usize)> {
+    match buf.iter().position(|byte| byte & 0x80 == 0) {
+        Some(last) if last < 10 => Ok((varint::decode(&buf[..(last + 1)]), last + 1)),
+        _                       => Err(Error::new(ErrorKind::Other, "Bitfield header is malformed.")),
+    }
+}
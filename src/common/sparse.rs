@@ -1,10 +1,12 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cmp;
+use std::io::Result;
+use std::sync::{Arc, RwLock};
+use std::ops::{Range};
 
 use common::pager::Pager;
 
 pub struct SparseBitfield {
-    pager:      Rc<RefCell<Pager>>,
+    pager:      Arc<RwLock<Pager>>,
     offset:     usize,
     size:       usize,
 }
@@ -12,13 +14,13 @@ pub struct SparseBitfield {
 impl SparseBitfield {
     pub fn new() -> SparseBitfield {
         SparseBitfield {
-            pager:      Rc::new(RefCell::new(Pager::new())),
+            pager:      Arc::new(RwLock::new(Pager::with_page_size(1024))),
             offset:     0,
             size:       1024,
         }
     }
 
-    pub fn with_pager(pager: Rc<RefCell<Pager>>, offset: usize, size: usize) -> SparseBitfield {
+    pub fn with_pager(pager: Arc<RwLock<Pager>>, offset: usize, size: usize) -> SparseBitfield {
         SparseBitfield {
             pager:      pager,
             offset:     offset,
@@ -26,12 +28,12 @@ impl SparseBitfield {
         }
     }
 
-    pub fn get(&self, index: u64) -> bool {
-        self.get_byte(index) & self.get_offset(index) != 0
+    pub fn get(&self, index: u64) -> Result<bool> {
+        Ok(try!(self.get_byte(index)) & self.get_offset(index) != 0)
     }
 
-    pub fn set(&mut self, index: u64, value: bool) -> bool {
-        let mut byte = self.get_byte(index);
+    pub fn set(&mut self, index: u64, value: bool) -> Result<bool> {
+        let mut byte = try!(self.get_byte(index));
         match value {
             true    => byte |= self.get_offset(index),
             false   => byte &= !(self.get_offset(index)),
@@ -39,28 +41,190 @@ impl SparseBitfield {
         self.set_byte(index, byte)
     }
 
-    pub fn get_byte(&self, index: u64) -> u8 {
+    pub fn present(&self, range: Range<u64>) -> Iter<SparseBitfield> {
+        Iter::new(self, range, true)
+    }
+
+    pub fn missing(&self, range: Range<u64>) -> Iter<SparseBitfield> {
+        Iter::new(self, range, false)
+    }
+
+    pub fn get_byte(&self, index: u64) -> Result<u8> {
         let page_num = self.get_page_num(index);
         let byte_num = self.get_byte_num(index);
-        match self.pager.borrow().get(page_num) {
-            None        => 0,
-            Some(page)  => page[byte_num]
+        {
+            let pager = self.pager.read().unwrap();
+            match pager.get(page_num) {
+                Some(page)                  => return Ok(page[byte_num]),
+                None if !pager.is_paged()   => return Ok(0),
+                None                        => (),
+            }
+        }
+
+        let mut pager = self.pager.write().unwrap();
+        match try!(pager.load(page_num)) {
+            true    => Ok(pager.get(page_num).map_or(0, |page| page[byte_num])),
+            false   => Ok(0),
         }
     }
 
-    pub fn set_byte(&mut self, index: u64, value: u8) -> bool {
+    pub fn set_byte(&mut self, index: u64, value: u8) -> Result<bool> {
         let page_num = self.get_page_num(index);
         let byte_num = self.get_byte_num(index);
-        self.pager.borrow_mut().set(page_num, byte_num, value)
+        self.pager.write().unwrap().set(page_num, byte_num, value)
+    }
+
+    pub fn and(&mut self, other: &SparseBitfield, range: Range<u64>) -> Result<()> {
+        self.combine(other, range, |a, b| a & b)
+    }
+
+    pub fn or(&mut self, other: &SparseBitfield, range: Range<u64>) -> Result<()> {
+        self.combine(other, range, |a, b| a | b)
+    }
+
+    pub fn and_not(&mut self, other: &SparseBitfield, range: Range<u64>) -> Result<()> {
+        self.combine(other, range, |a, b| a & !b)
+    }
+
+    pub fn count_ones(&self, range: Range<u64>) -> Result<u64> {
+        let mut total = 0;
+        let mut start = range.start / 8;
+        let end = (cmp::min(range.end, self.len()) + 7) / 8;
+
+        while range.start < range.end && start < end {
+            let len = cmp::min(end - start, self.size as u64 - start % self.size as u64);
+            let bytes = try!(self.read_bytes(start, len as usize));
+            for (i, &byte) in bytes.iter().enumerate() {
+                total += (byte & edge_mask(start + i as u64, &range)).count_ones() as u64;
+            }
+            start += len;
+        }
+
+        Ok(total)
     }
 
     pub fn len(&self) -> u64 {
-        let pager = self.pager.borrow();
-        pager.len() as u64 * pager.get_page_size() as u64 * 8
+        self.pages() * self.size as u64 * 8
     }
 
     pub fn pages(&self) -> u64 {
-        self.pager.borrow().len() as u64
+        self.pager.read().unwrap().len() as u64
+    }
+
+    // Whether the page holding `index` was ever written. A missing page has
+    // no bits set.
+    pub fn has_page(&self, index: u64) -> Result<bool> {
+        let page_num = self.get_page_num(index);
+        {
+            let pager = self.pager.read().unwrap();
+            if pager.get(page_num).is_some() || !pager.is_paged() {
+                return Ok(pager.get(page_num).is_some());
+            }
+        }
+        self.pager.write().unwrap().load(page_num)
+    }
+
+    // The first index in `range` whose bit is `value`. Pages that were never
+    // written hold no set bits, and bytes of all zeros or all ones can't
+    // hold the bit we're not looking for, so both are skipped whole.
+    pub fn find_first(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        let page_bits = self.size as u64 * 8;
+        let skip = if value { 0 } else { 255 };
+        let end = if value { cmp::min(range.end, self.len()) } else { range.end };
+        let mut index = range.start;
+
+        while index < end {
+            if value && !try!(self.has_page(index)) {
+                index = (index / page_bits + 1) * page_bits;
+                continue;
+            }
+
+            if index & 7 == 0 && try!(self.get_byte(index)) == skip {
+                index += 8;
+                continue;
+            }
+
+            if try!(self.get(index)) == value {
+                return Ok(Some(index));
+            }
+            index += 1;
+        }
+
+        Ok(None)
+    }
+
+    pub fn find_last(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        let page_bits = self.size as u64 * 8;
+        let skip = if value { 0 } else { 255 };
+        let mut index = if value { cmp::min(range.end, self.len()) } else { range.end };
+
+        while index > range.start {
+            if value && !try!(self.has_page(index - 1)) {
+                index = (index - 1) / page_bits * page_bits;
+                continue;
+            }
+
+            if index & 7 == 0 && try!(self.get_byte(index - 1)) == skip {
+                index -= 8;
+                continue;
+            }
+
+            index -= 1;
+            if try!(self.get(index)) == value {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Applies `op` to the bytes of both bitfields a page at a time, keeping
+    // this bitfield's bits outside `range` as they were. Past the end of
+    // both there's nothing but zeros, which none of the operations change.
+    fn combine<F>(&mut self, other: &SparseBitfield, range: Range<u64>, op: F) -> Result<()>
+                  where F: Fn(u8, u8) -> u8 {
+        let mut start = range.start / 8;
+        let end = (cmp::min(range.end, cmp::max(self.len(), other.len())) + 7) / 8;
+
+        while range.start < range.end && start < end {
+            let len = cmp::min(end - start, self.size as u64 - start % self.size as u64);
+            let mut bytes = try!(self.read_bytes(start, len as usize));
+            let others = try!(other.read_bytes(start, len as usize));
+
+            for i in 0..bytes.len() {
+                let mask = edge_mask(start + i as u64, &range);
+                bytes[i] = (op(bytes[i], others[i]) & mask) | (bytes[i] & !mask);
+            }
+
+            try!(self.write_bytes(start, &bytes));
+            start += len;
+        }
+
+        Ok(())
+    }
+
+    // `len` bytes from byte `start`, which must all be on one page.
+    fn read_bytes(&self, start: u64, len: usize) -> Result<Vec<u8>> {
+        let page_num = self.get_page_num(start * 8);
+        let byte_num = self.get_byte_num(start * 8);
+        if !try!(self.has_page(start * 8)) {
+            return Ok(vec![0u8; len]);
+        }
+
+        match self.pager.read().unwrap().get(page_num) {
+            Some(page)  => Ok(page[byte_num..(byte_num + len)].to_vec()),
+            None        => Ok(vec![0u8; len]),
+        }
+    }
+
+    fn write_bytes(&mut self, start: u64, bytes: &[u8]) -> Result<()> {
+        let page_num = self.get_page_num(start * 8);
+        let byte_num = self.get_byte_num(start * 8);
+        let mut pager = self.pager.write().unwrap();
+        for (i, &byte) in bytes.iter().enumerate() {
+            try!(pager.set(page_num, byte_num + i, byte));
+        }
+        Ok(())
     }
 
     fn get_offset(&self, index: u64) -> u8 {
@@ -75,4 +239,59 @@ impl SparseBitfield {
     fn get_byte_num(&self, index: u64) -> usize {
         self.offset + ((index as usize / 8) & (self.size - 1))
     }
-}
\ No newline at end of file
+}
+
+// The bits of byte `byte_num` that fall inside `range`.
+fn edge_mask(byte_num: u64, range: &Range<u64>) -> u8 {
+    let mut mask = 255u8;
+    if byte_num == range.start / 8 {
+        mask &= 255u8 << (range.start & 7);
+    }
+    if byte_num == (range.end - 1) / 8 && range.end & 7 != 0 {
+        mask &= !(255u8 << (range.end & 7));
+    }
+    mask
+}
+
+// Anything that can find its next set or unset bit, and so be walked with
+// `Iter`.
+pub trait FindFirst {
+    fn find_first(&self, value: bool, range: Range<u64>) -> Result<Option<u64>>;
+}
+
+impl FindFirst for SparseBitfield {
+    fn find_first(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        SparseBitfield::find_first(self, value, range)
+    }
+}
+
+// The indexes in `range` whose bit is `value`. Stops after the first error.
+pub struct Iter<'a, B: FindFirst + 'a> {
+    bitfield:   &'a B,
+    range:      Range<u64>,
+    value:      bool,
+}
+
+impl<'a, B: FindFirst + 'a> Iter<'a, B> {
+    pub fn new(bitfield: &'a B, range: Range<u64>, value: bool) -> Iter<'a, B> {
+        Iter { bitfield: bitfield, range: range, value: value }
+    }
+}
+
+impl<'a, B: FindFirst + 'a> Iterator for Iter<'a, B> {
+    type Item = Result<u64>;
+
+    fn next(&mut self) -> Option<Result<u64>> {
+        match self.bitfield.find_first(self.value, self.range.clone()) {
+            Ok(Some(index)) => {
+                self.range.start = index + 1;
+                Some(Ok(index))
+            },
+            Ok(None)        => None,
+            Err(err)        => {
+                self.range.start = self.range.end;
+                Some(Err(err))
+            },
+        }
+    }
+}
@@ -1,303 +1,558 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cmp;
+use std::io::Result;
+use std::sync::{Arc, RwLock};
 use std::ops::{Range};
 
 use common::flat;
-use common::pager::Pager;
-use common::sparse::SparseBitfield;
+use common::rle;
+use common::pager::{Pager, PageSource};
+use common::sparse::{SparseBitfield, FindFirst, Iter};
+
+// A data page is 1024 bytes and each index leaf covers 4 of them, so an
+// index node of depth 8 describes exactly one page. The index grows above
+// that with the data, so a run of full or empty pages is skipped at once.
+const INDEX_DEPTH: u64 = 8;
 
 pub struct Bitfield {
-    pager:      Rc<RefCell<Pager>>,
-    data:       SparseBitfield,
-    index:      SparseBitfield,
-    tree:       SparseBitfield,
+    pager:          Arc<RwLock<Pager>>,
+    data:           SparseBitfield,
+    index:          SparseBitfield,
+    tree:           SparseBitfield,
+    index_depth:    u64,
 }
 
 impl Bitfield {
-    fn with_pager(pager: Rc<RefCell<Pager>>) -> Bitfield {
+    fn with_pager(pager: Arc<RwLock<Pager>>) -> Bitfield {
         Bitfield {
-            pager:      Rc::clone(&pager),
-            data:       SparseBitfield::with_pager(Rc::clone(&pager), 0, 1024),
-            tree:       SparseBitfield::with_pager(Rc::clone(&pager), 1024, 2048),
-            index:      SparseBitfield::with_pager(Rc::clone(&pager), 1024 + 2048, 256),
+            pager:          Arc::clone(&pager),
+            data:           SparseBitfield::with_pager(Arc::clone(&pager), 0, 1024),
+            tree:           SparseBitfield::with_pager(Arc::clone(&pager), 1024, 2048),
+            index:          SparseBitfield::with_pager(Arc::clone(&pager), 1024 + 2048, 256),
+            index_depth:    INDEX_DEPTH,
         }
     }
 
     pub fn new() -> Bitfield {
-        let pager = Rc::new(RefCell::new(Pager::new()));
+        let pager = Arc::new(RwLock::new(Pager::new()));
         Bitfield::with_pager(pager)
     }
 
-    pub fn from_vec(vec: Vec<u8>) -> Bitfield {
-        let pager = Rc::new(RefCell::new(Pager::from_vec(vec)));
-        Bitfield::with_pager(pager)
+    pub fn from_vec(vec: Vec<u8>) -> Result<Bitfield> {
+        let pager = Arc::new(RwLock::new(Pager::from_vec(vec)));
+        let mut bitfield = Bitfield::with_pager(pager);
+        try!(bitfield.rebuild_index(None));
+        Ok(bitfield)
+    }
+
+    // Recomputes the index of the bitfield in `source`, handing finished
+    // pages to `write` as it goes so only about `budget` bytes are held.
+    pub fn reindex(source: Box<PageSource>, budget: usize, write: &mut FnMut(usize, Vec<u8>) -> Result<()>) -> Result<()> {
+        let pager = Arc::new(RwLock::new(try!(Pager::with_source(source, budget))));
+        let mut bitfield = Bitfield::with_pager(pager);
+        bitfield.rebuild_index(Some(write))
+    }
+
+    // For bitfields too big to hold in memory: pages are read from `source`
+    // as they're needed and only about `budget` bytes of them are kept.
+    pub fn paged(source: Box<PageSource>, budget: usize) -> Result<Bitfield> {
+        let pager = Arc::new(RwLock::new(try!(Pager::with_source(source, budget))));
+        let pages = pager.read().unwrap().len() as u64;
+        let mut bitfield = Bitfield::with_pager(pager);
+        bitfield.index_depth = index_depth(pages);
+
+        // The index may have been written before it grew this deep, and the
+        // nodes on its left edge are the only ones that can be missing.
+        for depth in (INDEX_DEPTH + 1)..(bitfield.index_depth + 1) {
+            try!(bitfield.update_index_node(depth, 0));
+        }
+        Ok(bitfield)
     }
 
-    pub fn get(&self, index: u64) -> bool {
+    pub fn get(&self, index: u64) -> Result<bool> {
         self.data.get(index)
     }
 
-    pub fn set(&mut self, index: u64, value: bool) -> bool {
-        self.set_data(index, value) && 
-        self.set_tree(index, value) &&
-        self.set_index(index)
+    // Run-length encodes the blocks in `range` for a `Have` message. Whole
+    // bytes are encoded, so the message starts at `range.start & !7`; bits
+    // outside `range` are left clear. Bytes are sent high bit first.
+    pub fn to_rle(&self, range: Range<u64>) -> Result<Vec<u8>> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+
+        let first = range.start / 8;
+        let last = (range.end + 7) / 8;
+        let mut bytes: Vec<u8> = Vec::with_capacity((last - first) as usize);
+        for byte in first..last {
+            bytes.push(try!(self.data.get_byte(byte * 8)));
+        }
+
+        bytes[0] &= !0u8 << (range.start & 7);
+        if range.end & 7 != 0 {
+            let end = bytes.len() - 1;
+            bytes[end] &= !(!0u8 << (range.end & 7));
+        }
+
+        let bytes: Vec<u8> = bytes.into_iter().map(rle::swap_bit_order).collect();
+        Ok(rle::encode(&bytes))
+    }
+
+    // The inverse of `to_rle` for a message starting at `start`, which may
+    // not cover blocks from `max_length` on. Only the data and its index
+    // are filled in: a peer claiming blocks doesn't make them verified.
+    pub fn from_rle(start: u64, buf: &[u8], max_length: u64) -> Result<Bitfield> {
+        let first = start & !7;
+        let max_len = if max_length > first { (max_length - first - 1) / 8 + 1 } else { 0 };
+        let bytes = try!(rle::decode(buf, cmp::min(max_len, usize::max_value() as u64) as usize));
+        let mut bitfield = Bitfield::new();
+        let end = first + 8 * bytes.len() as u64;
+
+        for (i, &byte) in bytes.iter().enumerate() {
+            let index = first + 8 * i as u64;
+            let mut byte = rle::swap_bit_order(byte);
+            if index + 8 > max_length {
+                byte &= !(!0u8 << (max_length - index));
+            }
+            try!(bitfield.data.set_byte(index, byte));
+        }
+
+        try!(bitfield.update_index(first..end));
+        Ok(bitfield)
     }
 
-    fn set_data(&mut self, index: u64, value: bool) -> bool {
+    pub fn set(&mut self, index: u64, value: bool) -> Result<bool> {
+        if !try!(self.set_data(index, value)) {
+            return Ok(false);
+        }
+        try!(self.set_tree(index, value));
+        try!(self.set_index(index));
+        Ok(true)
+    }
+
+    // Like calling `set` for every index in `range`, but whole data bytes
+    // are written at once, each index byte is recomputed once and the tree
+    // is updated a level at a time. Touched pages show up in `last_updated`
+    // once each.
+    pub fn set_range(&mut self, range: Range<u64>, value: bool) -> Result<bool> {
+        if !try!(self.set_data_range(range.clone(), value)) {
+            return Ok(false);
+        }
+        try!(self.set_tree_range(range, value));
+        Ok(true)
+    }
+
+    // Drops blocks without touching the tree, so nodes we've verified stay
+    // that way and `blocks` doesn't change.
+    pub fn clear(&mut self, range: Range<u64>) -> Result<bool> {
+        self.set_data_range(range, false)
+    }
+
+    // Set operations over the blocks in `range`, done a page at a time. Like
+    // `clear` they leave the tree alone, so they're meant for working out
+    // what to request rather than recording what we've verified.
+    pub fn and(&mut self, other: &Bitfield, range: Range<u64>) -> Result<()> {
+        try!(self.data.and(&other.data, range.clone()));
+        self.update_index(range)
+    }
+
+    pub fn or(&mut self, other: &Bitfield, range: Range<u64>) -> Result<()> {
+        try!(self.data.or(&other.data, range.clone()));
+        self.update_index(range)
+    }
+
+    pub fn and_not(&mut self, other: &Bitfield, range: Range<u64>) -> Result<()> {
+        try!(self.data.and_not(&other.data, range.clone()));
+        self.update_index(range)
+    }
+
+    pub fn data(&self) -> &SparseBitfield {
+        &self.data
+    }
+
+    pub fn present(&self, range: Range<u64>) -> Iter<Bitfield> {
+        Iter::new(self, range, true)
+    }
+
+    pub fn missing(&self, range: Range<u64>) -> Iter<Bitfield> {
+        Iter::new(self, range, false)
+    }
+
+    fn set_data(&mut self, index: u64, value: bool) -> Result<bool> {
         self.data.set(index, value)
     }
 
-    fn set_tree(&mut self, index: u64, value: bool) -> bool {
-        let mut current = index * 2;
-        if !self.tree.set(current, value) { return false; }
-        while self.tree.get(flat::sibling(current)) {
-            current = flat::parent(current);
-            if !self.tree.set(current, true) { break; }
+    fn set_data_range(&mut self, range: Range<u64>, value: bool) -> Result<bool> {
+        if range.start >= range.end {
+            return Ok(false);
         }
-        true
+
+        let changed = try!(fill(&mut self.data, range.clone(), value));
+        if changed {
+            try!(self.update_index(range));
+        }
+
+        Ok(changed)
+    }
+
+    fn update_index(&mut self, range: Range<u64>) -> Result<()> {
+        let end = cmp::min(range.end, self.data.len());
+        if range.start >= end {
+            return Ok(());
+        }
+        for byte in (range.start / 8)..((end + 7) / 8) {
+            try!(self.set_index(byte * 8));
+        }
+        Ok(())
+    }
+
+    // A tree node is set when everything below it is, so setting a leaf can
+    // complete its parents and clearing one clears them.
+    fn set_tree(&mut self, index: u64, value: bool) -> Result<bool> {
+        let mut iter = flat::TreeIterator::new(index * 2);
+        if !try!(self.tree.set(iter.index(), value)) { return Ok(false); }
+        if !value {
+            while try!(self.tree.set(iter.parent(), false)) {}
+            return Ok(true);
+        }
+        while try!(self.tree.get(iter.sibling())) {
+            if !try!(self.tree.set(iter.parent(), true)) { break; }
+        }
+        Ok(true)
     }
 
-    fn set_index(&mut self, index: u64) -> bool {
-        let value = self.data.get_byte(index);
-        let o = index as usize & 3;
-        let start = index * 2;
-        let tup = match value {
-            255 => 0b11000000,
-            0   => 0b00000000,
-            _   => 0b01000000
-        };
-        let mask: u8 = !(3 << (6 - (o * 2)));
-        let mut byte = (self.index.get_byte(start) & mask) | tup << (2 * 0);
-        let max_len = self.index.pages() * 256;
+    // Splits `range` into the largest aligned subtrees it holds. A subtree's
+    // nodes sit next to each other in the tree, so each is filled in one go
+    // and only the path above its root is walked like `set_tree` does.
+    fn set_tree_range(&mut self, range: Range<u64>, value: bool) -> Result<()> {
+        let mut start = range.start;
+
+        while start < range.end {
+            let mut depth = 0;
+            while depth < 63 && start & (2 << depth) - 1 == 0 && start + (2 << depth) <= range.end {
+                depth += 1;
+            }
+
+            let root = flat::index(depth, start >> depth);
+            try!(fill(&mut self.tree, flat::left_span(root)..(flat::right_span(root) + 1), value));
+
+            let mut iter = flat::TreeIterator::new(root);
+            if value {
+                while try!(self.tree.get(iter.sibling())) {
+                    if !try!(self.tree.set(iter.parent(), true)) { break; }
+                }
+            } else {
+                while try!(self.tree.set(iter.parent(), false)) {}
+            }
 
+            start += 1 << depth;
+        }
+        Ok(())
+    }
+
+    // Each data byte has two bits in an index leaf: 11 when full, 00 when
+    // empty and 01 otherwise. Parents merge pairs of their children's
+    // entries the same way, up to a root covering all the data.
+    fn set_index(&mut self, index: u64) -> Result<bool> {
+        let byte_num = index / 8;
+        while byte_num >= 4 << self.index_depth {
+            self.index_depth += 1;
+            let depth = self.index_depth;
+            try!(self.update_index_node(depth, 0));
+        }
+
+        let shift = 6 - 2 * (byte_num & 3);
+        let start = 2 * (byte_num / 4);
+        let value = index_value(try!(self.data.get_byte(index)));
+        let mut byte = (try!(self.get_index_byte(start)) & !(3 << shift)) | (value << shift);
         let mut current = start;
 
-        while current < max_len && self.index.set_byte(index, value) {
-            let sibling = self.index.get_byte(flat::sibling(current));
+        while try!(self.set_index_byte(current, byte)) && flat::depth(current) < self.index_depth {
+            let sibling = try!(self.get_index_byte(flat::sibling(current)));
             if flat::is_left(current) {
-                byte = (convert_to_index(byte) << 4) | convert_to_index(sibling);
+                byte = merge_index(byte, sibling);
             } else {
-                byte = (convert_to_index(sibling) << 4) | convert_to_index(byte);
+                byte = merge_index(sibling, byte);
             }
 
             current = flat::parent(current);
         }
 
-        current != start
-    }
-
-    pub fn total(&self, range: Range<u64>) -> u64 {
-        let start = (range.start & 7) as u8;
-        let end = (range.end & 7) as u8;
-        let pos = range.start - start as u64 / 8;
-        let last = range.end - end as u64 / 8;
-        let left_mask = match start {
-            1   => 255 - 0b10000000,
-            2   => 255 - 0b11000000,
-            3   => 255 - 0b11100000,
-            4   => 255 - 0b11110000,
-            5   => 255 - 0b11111000,
-            6   => 255 - 0b11111100,
-            7   => 255 - 0b11111110,
-            _   => 255,
-        };
-        let right_mask = match end {
-            1   => 0b10000000,
-            2   => 0b11000000,
-            3   => 0b11100000,
-            4   => 0b11110000,
-            5   => 0b11111000,
-            6   => 0b11111100,
-            7   => 0b11111110,
-            _   => 0,
-        };
-        let byte = self.data.get_byte(pos);
-        if pos == last {
-            return (byte & left_mask & right_mask).count_ones() as u64;
-        }
-        let mut total = (byte & left_mask).count_ones() as u64;
-        for i in (pos + 1)..last {
-            total += self.data.get_byte(i).count_ones() as u64;
-        }
-        total += (self.data.get_byte(last) & right_mask).count_ones() as u64;
-        total
-    }
-
-    pub fn blocks(&self) -> u64 {
+        Ok(current != start)
+    }
+
+    // Index bytes written by older versions can't be trusted, so they're
+    // recomputed from the data when a bitfield is loaded, a level at a time.
+    // With `write`, changed pages are written out every 256 index bytes so
+    // a paged pager can drop them again.
+    fn rebuild_index(&mut self, mut write: Option<&mut FnMut(usize, Vec<u8>) -> Result<()>>) -> Result<()> {
+        let pages = self.pager.read().unwrap().len() as u64;
+        self.index_depth = index_depth(pages);
+
+        let mut nodes = pages * 1024 / 4;
+        for leaf in 0..nodes {
+            let mut byte = 0;
+            for i in 0..4 {
+                byte |= index_value(try!(self.data.get_byte((4 * leaf + i) * 8))) << (6 - 2 * i);
+            }
+            try!(self.set_index_byte(2 * leaf, byte));
+            if leaf & 255 == 255 {
+                try!(self.write_updated(&mut write));
+            }
+        }
+
+        for depth in 1..(self.index_depth + 1) {
+            nodes = (nodes + 1) / 2;
+            for offset in 0..nodes {
+                try!(self.update_index_node(depth, offset));
+                if offset & 255 == 255 {
+                    try!(self.write_updated(&mut write));
+                }
+            }
+        }
+        self.write_updated(&mut write)
+    }
+
+    fn write_updated(&mut self, write: &mut Option<&mut FnMut(usize, Vec<u8>) -> Result<()>>) -> Result<()> {
+        if let Some(ref mut write) = *write {
+            while let Some((offset, data)) = self.last_updated() {
+                try!(write(offset, data));
+            }
+        }
+        Ok(())
+    }
+
+    fn update_index_node(&mut self, depth: u64, offset: u64) -> Result<bool> {
+        let left = try!(self.get_index_byte(flat::index(depth - 1, 2 * offset)));
+        let right = try!(self.get_index_byte(flat::index(depth - 1, 2 * offset + 1)));
+        self.set_index_byte(flat::index(depth, offset), merge_index(left, right))
+    }
+
+    fn get_index_byte(&self, index: u64) -> Result<u8> {
+        self.index.get_byte(index * 8)
+    }
+
+    fn set_index_byte(&mut self, index: u64, value: u8) -> Result<bool> {
+        self.index.set_byte(index * 8, value)
+    }
+
+    // The two bit index entry covering data byte `byte_num` at `depth`.
+    fn index_slot(&self, depth: u64, byte_num: u64) -> Result<u8> {
+        let byte = try!(self.get_index_byte(flat::index(depth, byte_num >> (depth + 2))));
+        Ok((byte >> (6 - 2 * ((byte_num >> depth) & 3))) & 3)
+    }
+
+    // `byte_num` is entirely `state`; returns the largest run of such bytes
+    // around it the index knows about.
+    fn skip_run(&self, byte_num: u64, state: u8) -> Result<Range<u64>> {
+        let mut depth = 0;
+        while depth < self.index_depth && try!(self.index_slot(depth + 1, byte_num)) == state {
+            depth += 1;
+        }
+        Ok(((byte_num >> depth) << depth)..(((byte_num >> depth) + 1) << depth))
+    }
+
+    // The first block in `range` whose bit is `value`, skipping whatever the
+    // index says is all full or all empty.
+    pub fn find_first(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        let (skip, state) = if value { (0, 0) } else { (255, 3) };
+        let end = if value { cmp::min(range.end, self.data.len()) } else { range.end };
+        let mut index = range.start;
+
+        while index < end {
+            if index & 7 == 0 && try!(self.data.get_byte(index)) == skip {
+                index = 8 * try!(self.skip_run(index / 8, state)).end;
+                continue;
+            }
+
+            if try!(self.data.get(index)) == value {
+                return Ok(Some(index));
+            }
+            index += 1;
+        }
+
+        Ok(None)
+    }
+
+    pub fn find_last(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        let (skip, state) = if value { (0, 0) } else { (255, 3) };
+        let mut index = if value { cmp::min(range.end, self.data.len()) } else { range.end };
+
+        while index > range.start {
+            if index & 7 == 0 && try!(self.data.get_byte(index - 1)) == skip {
+                index = 8 * try!(self.skip_run((index - 1) / 8, state)).start;
+                continue;
+            }
+
+            index -= 1;
+            if try!(self.data.get(index)) == value {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn total(&self, range: Range<u64>) -> Result<u64> {
+        self.data.count_ones(range)
+    }
+
+    pub fn blocks(&self) -> Result<u64> {
         let mut top = 0;
-        let mut next = 0;
+        let mut iter = flat::TreeIterator::new(0);
         let max = self.tree.len();
 
-        while flat::right_span(next) < max {
-            next = flat::parent(next);
-            if self.tree.get(next) {
-                top = next;
+        while flat::right_span(iter.index()) < max {
+            if try!(self.tree.get(iter.parent())) {
+                top = iter.index();
             }
         }
 
-        if !self.tree.get(top) {
-            return 0;
+        if !try!(self.tree.get(top)) {
+            return Ok(0);
         }
-        
 
-        match self.verfied_by(top) {
-            Some(val) => val / 2,
-            None      => 0,
+        match try!(self.verfied_by(top)) {
+            Some(val) => Ok(val / 2),
+            None      => Ok(0),
         }
     }
 
-    pub fn roots(&self) -> Vec<u64> {
-        flat::full_roots(2 * self.blocks())
+    pub fn roots(&self) -> Result<Vec<u64>> {
+        Ok(flat::full_roots(2 * try!(self.blocks())))
     }
 
-    pub fn verfied_by(&self, index: u64) -> Option<u64> {
-        if !self.tree.get(index) { return None; }
-        let mut depth = flat::depth(index);
-        let mut top = index;
-        let mut parent = flat::parent_with_depth(index, depth);
-        depth += 1;
+    pub fn verfied_by(&self, index: u64) -> Result<Option<u64>> {
+        if !try!(self.tree.get(index)) { return Ok(None); }
+        let mut iter = flat::TreeIterator::new(index);
 
         // Find current root.
-        while self.tree.get(parent) && self.tree.get(flat::sibling(top)) {
-            top = parent;
-            parent = flat::parent_with_depth(top, depth);
-            depth += 1;
+        loop {
+            let top = iter.index();
+            if !try!(self.tree.get(iter.sibling())) || !try!(self.tree.get(iter.parent())) {
+                iter.seek(top);
+                break;
+            }
         }
 
         // Extend right down.
-        depth -= 1;
-        while depth > 0 {
-            parent = flat::index(depth, flat::offset_with_depth(top, depth) + 1);
-            top = match flat::child_with_depth(parent, depth, true) {
-                Some(child) => child,
-                None => return None,   
-            };
-            depth -= 1;
-
-            while !self.tree.get(top) && depth > 0 { 
-                top = match flat::child_with_depth(top, depth, true) {
-                    Some(child) => child,
-                    None => return None,   
-                };
-                depth -= 1;
+        while iter.depth() > 0 {
+            iter.next_node();
+            iter.left_child();
+            while !try!(self.tree.get(iter.index())) && iter.depth() > 0 {
+                iter.left_child();
             }
         }
 
-        match self.tree.get(top) {
-            true    => Some(top + 2),
-            false   => Some(top),
+        match try!(self.tree.get(iter.index())) {
+            true    => Ok(Some(iter.index() + 2)),
+            false   => Ok(Some(iter.index())),
         }
     }
 
-    pub fn digest(&self, index: u64) -> u64 {
-        if self.tree.get(index) {
-            return 1;
+    pub fn digest(&self, index: u64) -> Result<u64> {
+        if try!(self.tree.get(index)) {
+            return Ok(1);
         }
 
         let mut digest = 0u64;
-        let mut next = flat::sibling(index);
-        let max = match next + 2 > self.tree.len() {
-            true    => next + 2,
-            false   => self.tree.len()
-        };
-
+        let mut iter = flat::TreeIterator::new(index);
+        let mut next = iter.sibling();
+        let max = cmp::max(next + 2, self.tree.len());
         let mut bit = 2u64;
-        let mut depth = flat::depth(index);
-        let mut parent = flat::parent_with_depth(next, depth);
-        depth += 1;
+        let mut parent = iter.parent();
 
         while flat::right_span(next) < max || flat::left_span(parent) > 0 {
-            if self.tree.get(next) {
+            if try!(self.tree.get(next)) {
                 digest |= bit;
             }
 
-            if self.tree.get(parent) {
+            if try!(self.tree.get(parent)) {
                 digest |= 2 * bit + 1;
                 if digest + 1 == 4 * bit {
-                    return 1;
+                    return Ok(1);
                 }
-                return digest;
+                return Ok(digest);
             }
 
-            next = flat::sibling(parent);
-            parent = flat::parent_with_depth(next, depth);
-            depth += 1;
+            next = iter.sibling();
+            parent = iter.parent();
             bit *= 2;
         }
 
-        digest
-    }
-
-    // pub fn proof(&self, index: u64, opts: ProofOpts) -> Option<(Vec<u64>, u64)> {
-    //     if !self.get(index) { return None; }
-        
-    //     let mut nodes: Vec<u64> = Vec::new();
-    //     let mut digest = opts.digest;
-    //     let mut remote = opts.remote;
-        
-    //     if opts.hash { nodes.push(index); }
-    //     if digest == 1 { return Some((nodes, 0)); }
-
-    //     let mut roots: Vec<u64>;
-    //     let mut sibling;
-    //     let mut next = index;
-        
-    //     digest >>= 1;
-    //     while digest > 0 {
-    //         if digest == 1 && digest & 1 != 0 {
-    //             if self.get(next) { remote.set(next); }
-    //             if flat::sibling(next) < next { next = flat::sibling(next); }
-    //             roots = flat::full_roots(flat::right_span(next) + 2);
-    //             for &root in &roots {
-    //                 if self.get(root) { remote.set(root); }
-    //             }
-    //             break;
-    //         }
-
-    //         sibling = flat::sibling(next);
-    //         if digest & 1 > 0 && self.get(sibling) { remote.set(sibling); }
-    //         next = flat::parent(next);
-    //         digest >>= 1;
-    //     }
-
-    //     next = index;
-
-    //     while !remote.get(next) {
-    //         sibling = flat::sibling(next);
-    //         if !self.get(sibling) {
-    //             match self.verfied_by(next) {
-    //                 None => return None,
-    //                 Some(val) => {
-    //                     roots = flat::full_roots(val);
-    //                     for &root in &roots {
-    //                         if root != next && !remote.get(root) { nodes.push(root); }
-    //                     }
-    //                     return Some((nodes, val));
-    //                 }
-    //             }
-    //         } else {
-    //             if !remote.get(sibling) { nodes.push(sibling); }
-    //         }
-
-    //         next = flat::parent(next);
-    //     }
-
-    //     Some((nodes, 0))
-    // }
-
-    pub fn to_vec(&self) -> Vec<u8> {
-        let pager = self.pager.borrow();
+        Ok(digest)
+    }
+
+    // The tree nodes someone needs to verify `index`, leaving out the ones
+    // `opts.digest` says they already have. Also returns the node whose
+    // roots have to be signed for, or 0 if they can verify against nodes
+    // they hold.
+    pub fn proof(&self, index: u64, opts: ProofOpts) -> Result<Option<(Vec<u64>, u64)>> {
+        if !try!(self.tree.get(index)) { return Ok(None); }
+
+        let mut nodes: Vec<u64> = Vec::new();
+        let mut digest = opts.digest;
+        let mut remote = opts.remote;
+
+        if opts.hash { nodes.push(index); }
+        if digest == 1 { return Ok(Some((nodes, 0))); }
+
+        let has_root = digest & 1 != 0;
+        let mut iter = flat::TreeIterator::new(index);
+
+        digest >>= 1;
+        while digest > 0 {
+            if digest == 1 && has_root {
+                if try!(self.tree.get(iter.index())) { try!(remote.set(iter.index(), true)); }
+                if iter.is_right() { iter.sibling(); }
+                for root in flat::full_roots(iter.right_span() + 2) {
+                    if try!(self.tree.get(root)) { try!(remote.set(root, true)); }
+                }
+                break;
+            }
+
+            let sibling = iter.sibling();
+            if digest & 1 != 0 && try!(self.tree.get(sibling)) { try!(remote.set(sibling, true)); }
+            iter.parent();
+            digest >>= 1;
+        }
+
+        iter.seek(index);
+        while !try!(remote.get(iter.index())) {
+            let next = iter.index();
+            let sibling = iter.sibling();
+            if !try!(self.tree.get(sibling)) {
+                let verified_by = match try!(self.verfied_by(next)) {
+                    Some(val)   => val,
+                    None        => return Ok(None),
+                };
+                for root in flat::full_roots(verified_by) {
+                    if root != next && !try!(remote.get(root)) { nodes.push(root); }
+                }
+                return Ok(Some((nodes, verified_by)));
+            }
+
+            if !try!(remote.get(sibling)) { nodes.push(sibling); }
+            try!(remote.set(next, true));
+            iter.parent();
+        }
+
+        Ok(Some((nodes, 0)))
+    }
+
+    // Pages a paged bitfield doesn't hold are read from its source, but not
+    // kept.
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let pager = self.pager.read().unwrap();
         let page_size = pager.get_page_size();
         let mut result: Vec<u8> = vec![0u8; pager.len() * page_size as usize];
-        for (&index, value) in pager.iter() {
-            let start: usize = index * page_size;
-            result[start..(start + page_size)].copy_from_slice(value.as_slice());
+        for (index, page) in result.chunks_mut(page_size).enumerate() {
+            try!(pager.read(index, page));
         }
-        result
+        Ok(result)
     }
 
     pub fn last_updated(&mut self) -> Option<(usize, Vec<u8>)> {
-        let mut pager = self.pager.borrow_mut();
+        let mut pager = self.pager.write().unwrap();
         match pager.last_updated() {
             None        => None,
             Some(num)   => Some((num * pager.get_page_size(), pager.get(num).unwrap().to_vec()))
@@ -305,33 +560,78 @@ impl Bitfield {
     }
 }
 
-// pub struct ProofOpts {
-//     remote:     TreeBitfield,
-//     digest:     u64,
-//     hash:       bool,
-// }
-
-// impl ProofOpts {
-//     pub fn new() -> ProofOpts {
-//         ProofOpts {
-//             remote:     TreeBitfield::new(),
-//             digest:     0,
-//             hash:       false,
-//         }
-//     }
-
-//     pub fn set_remote(&mut self, remote: TreeBitfield) {
-//         self.remote = remote;
-//     }
-
-//     pub fn set_digest(&mut self, digest: u64) {
-//         self.digest = digest;
-//     }
-
-//     pub fn set_hash(&mut self, hash: bool) {
-//         self.hash = hash;
-//     }
-// }
+impl FindFirst for Bitfield {
+    fn find_first(&self, value: bool, range: Range<u64>) -> Result<Option<u64>> {
+        Bitfield::find_first(self, value, range)
+    }
+}
+
+pub struct ProofOpts {
+    remote:     SparseBitfield,
+    digest:     u64,
+    hash:       bool,
+}
+
+impl ProofOpts {
+    pub fn new() -> ProofOpts {
+        ProofOpts {
+            remote:     SparseBitfield::new(),
+            digest:     0,
+            hash:       false,
+        }
+    }
+
+    pub fn set_remote(&mut self, remote: SparseBitfield) {
+        self.remote = remote;
+    }
+
+    pub fn set_digest(&mut self, digest: u64) {
+        self.digest = digest;
+    }
+
+    pub fn set_hash(&mut self, hash: bool) {
+        self.hash = hash;
+    }
+}
+
+// Sets every bit in `range` of `bits`, whole bytes at a time where it can.
+fn fill(bits: &mut SparseBitfield, range: Range<u64>, value: bool) -> Result<bool> {
+    let byte = if value { 255 } else { 0 };
+    let mut changed = false;
+    let mut index = range.start;
+
+    while index < range.end {
+        if index & 7 == 0 && index + 8 <= range.end {
+            changed |= try!(bits.set_byte(index, byte));
+            index += 8;
+        } else {
+            changed |= try!(bits.set(index, value));
+            index += 1;
+        }
+    }
+    Ok(changed)
+}
+
+// The smallest index with a root over `pages` data pages.
+fn index_depth(pages: u64) -> u64 {
+    let mut depth = INDEX_DEPTH;
+    while 1 << (depth - INDEX_DEPTH) < pages {
+        depth += 1;
+    }
+    depth
+}
+
+fn merge_index(left: u8, right: u8) -> u8 {
+    (convert_to_index(left) << 4) | convert_to_index(right)
+}
+
+fn index_value(byte: u8) -> u8 {
+    match byte {
+        255 => 0b11,
+        0   => 0b00,
+        _   => 0b01,
+    }
+}
 
 fn convert_to_index(value: u8) -> u8 {
     let left = match (value & (15 << 4)) >> 4 {
@@ -1,67 +1,243 @@
 use std::io;
 use std::io::{Result};
 use std::ops::{Range};
+use std::marker::PhantomData;
 
-use rand::OsRng;
+use rand::{OsRng, Rng};
 use sha2::Sha512;
-use blake2::{Blake2b, Digest};
-use ed25519_dalek::{Keypair, Signature};
+use blake2::Blake2b;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
 
-use common::merkle::Tree;
-use core::storage::Storage;
-use core::bitfield::Bitfield;
+use common::merkle::{Node, Tree, TreeHash};
+use core::storage::{Storage, StorageState, FileType, FileHeader};
+use core::bitfield::{Bitfield, ProofOpts};
+use core::keystore::KeyStore;
+use core::signer::{Signer, Verifier, Ed25519Signer, Ed25519Verifier};
 
 // const LEAF_TYPE : &'static [u8] = &[0];
 // const PARENT_TYPE : &'static [u8] = &[1];
 const ROOT_TYPE: &'static [u8] = &[2];
 // const HYPERCORE: &'static [u8] = b"hypercore";
 
-pub struct Hypercore<T: Storage> {
+// `H` hashes the merkle tree; a feed always has to be reopened with the
+// hash it was created with.
+pub struct Hypercore<T: Storage, H: TreeHash = Blake2b> {
     storage:    T,
     blocks:     u64,
     length:     u64,
     key:        [u8; 32],
-    secret:     [u8; 64],
+    secret:     Option<[u8; 64]>,
+    signer:     Option<Box<Signer + Send + Sync>>,
+    verifier:   Box<Verifier + Send + Sync>,
     merkle:     Tree,
     bitfield:   Bitfield,
+    hash:       PhantomData<H>,
+}
+
+// What a `Data` message carries besides the block: the nodes needed to
+// verify it and, when they lead to our roots, the signature over them.
+pub struct Proof {
+    pub index:      u64,
+    pub nodes:      Vec<Node>,
+    pub signature:  Option<Vec<u8>>,
+}
+
+// How to open a feed; the settings combine freely. The keypair, seed and
+// rng only matter for a new feed, and a signer or verifier takes the place
+// of a secret key held by the feed.
+pub struct HypercoreBuilder<'a, H: TreeHash = Blake2b> {
+    keystore:   Option<&'a mut KeyStore>,
+    seed:       Option<[u8; 32]>,
+    keypair:    Option<Keypair>,
+    signer:     Option<Box<Signer + Send + Sync>>,
+    reader:     Option<([u8; 32], Box<Verifier + Send + Sync>)>,
+    budget:     Option<usize>,
+    hash:       PhantomData<H>,
+}
+
+impl<'a> HypercoreBuilder<'a> {
+    pub fn new() -> HypercoreBuilder<'a> {
+        HypercoreBuilder::with_hash()
+    }
+}
+
+impl<'a, H: TreeHash> HypercoreBuilder<'a, H> {
+    // `H` hashes the merkle tree of a new feed, and has to match the one an
+    // existing feed was created with.
+    pub fn with_hash() -> HypercoreBuilder<'a, H> {
+        HypercoreBuilder {
+            keystore:   None,
+            seed:       None,
+            keypair:    None,
+            signer:     None,
+            reader:     None,
+            budget:     None,
+            hash:       PhantomData,
+        }
+    }
+
+    // Keeps the secret in `keystore` rather than in the feed's own storage.
+    pub fn keystore(mut self, keystore: &'a mut KeyStore) -> HypercoreBuilder<'a, H> {
+        self.keystore = Some(keystore);
+        self
+    }
+
+    // The same seed always gives the same keypair, so fixtures are
+    // reproducible.
+    pub fn seed(mut self, seed: [u8; 32]) -> HypercoreBuilder<'a, H> {
+        self.seed = Some(seed);
+        self.keypair = None;
+        self
+    }
+
+    pub fn rng<R: Rng>(mut self, rng: &mut R) -> HypercoreBuilder<'a, H> {
+        self.keypair = Some(Keypair::generate::<Sha512>(rng));
+        self.seed = None;
+        self
+    }
+
+    // Signs with `signer` instead of a secret key held by the feed. A new
+    // feed takes the signer's public key; an existing one has to match it.
+    pub fn signer(mut self, signer: Box<Signer + Send + Sync>) -> HypercoreBuilder<'a, H> {
+        self.signer = Some(signer);
+        self
+    }
+
+    // Opens someone else's feed read-only, checking its signatures with
+    // `verifier`, e.g. for a scheme only the writer can sign with. A new
+    // feed takes `key`; an existing one has to have it.
+    pub fn verifier(mut self, key: [u8; 32], verifier: Box<Verifier + Send + Sync>) -> HypercoreBuilder<'a, H> {
+        self.reader = Some((key, verifier));
+        self
+    }
+
+    // Keeps only about `bytes` of the bitfield in memory, reading the rest
+    // from storage as needed. Fails if the storage can't page its bitfield.
+    pub fn bitfield_budget(mut self, bytes: usize) -> HypercoreBuilder<'a, H> {
+        self.budget = Some(bytes);
+        self
+    }
+
+    pub fn build<T: Storage>(self, storage: T) -> Result<Hypercore<T, H>> {
+        Hypercore::open(storage, self)
+    }
 }
 
 impl<T: Storage> Hypercore<T> {
-    pub fn new(mut storage: T) -> Result<Hypercore<T>> {
-        try!(storage.setup());
+    pub fn new(storage: T) -> Result<Hypercore<T>> {
+        HypercoreBuilder::new().build(storage)
+    }
+}
+
+impl<T: Storage, H: TreeHash> Hypercore<T, H> {
+    fn open(mut storage: T, options: HypercoreBuilder<H>) -> Result<Hypercore<T, H>> {
+        let HypercoreBuilder { mut keystore, seed, keypair, mut signer, reader, budget, .. } = options;
+        let pair = match (keypair, seed) {
+            (Some(pair), _)     => Some(pair),
+            (None, Some(seed))  => {
+                let secret = match SecretKey::from_bytes(&seed) {
+                    Ok(secret)  => secret,
+                    Err(_)      => return Err(io::Error::new(io::ErrorKind::Other, "Invalid seed.")),
+                };
+                let public = PublicKey::from_secret::<Sha512>(&secret);
+                Some(Keypair { secret: secret, public: public })
+            },
+            (None, None)        => None,
+        };
 
-        let state = try!(storage.get_state());
-        let mut generate_key = true;
-        let mut key = [0u8; 32];
-        let mut secret = [0u8; 64];
+        let mut headers = vec![(FileType::Tree, try!(FileHeader::new(FileType::Tree).unwrap().with_algorithm(H::name())))];
+        let scheme = match (&signer, &reader) {
+            (&Some(ref signer), _)              => Some(signer.scheme()),
+            (&None, &Some((_, ref verifier)))   => Some(verifier.scheme()),
+            (&None, &None)                      => None,
+        };
+        if let Some(scheme) = scheme {
+            headers.push((FileType::Signatures, try!(FileHeader::new(FileType::Signatures).unwrap().with_algorithm(scheme))));
+        }
+        try!(storage.setup_with(&headers));
+
+        let source = match budget {
+            Some(_) => match try!(storage.page_source()) {
+                Some(source)    => Some(source),
+                None            => return Err(io::Error::new(io::ErrorKind::Other, "Storage can't page its bitfield.")),
+            },
+            None    => None,
+        };
+        let state = match source {
+            Some(_) => StorageState { bitfield: Vec::new(), key: try!(storage.get_key()), secret: try!(storage.get_secret()) },
+            None    => try!(storage.get_state()),
+        };
+        let key;
+        let mut secret = None;
 
         // Add discovery_key
 
-        let bitfield = Bitfield::from_vec(state.bitfield);
-        let blocks = bitfield.blocks();
-
-        if state.key.is_some() && state.secret.is_some() {
-            key = state.key.unwrap();
-            secret = state.secret.unwrap();
-            let message: &[u8] = b"Verify Me.";
-            if let Ok(pair) = Keypair::from_bytes(&[&secret[..32], &key[..]].concat()) {
-                let signature: Signature = pair.sign::<Sha512>(message);
-                if pair.verify::<Sha512>(message, &signature) {
-                    generate_key = false;
+        let bitfield = match (source, budget) {
+            (Some(source), Some(bytes)) => try!(Bitfield::paged(source, bytes)),
+            _                           => try!(Bitfield::from_vec(state.bitfield)),
+        };
+        let blocks = try!(bitfield.blocks());
+
+        let state_secret = match (keystore.as_ref(), state.key) {
+            (Some(store), Some(ref key))    => try!(store.get_secret(key)),
+            (Some(_), None)                 => None,
+            (None, _)                       => state.secret,
+        };
+
+        // A feed with a key but no matching secret is read-only until a
+        // keypair is imported; only a feed without a key gets a new one.
+        if let Some(ref signer) = signer {
+            key = signer.public_key();
+            match state.key {
+                Some(stored) if stored != key   => return Err(io::Error::new(io::ErrorKind::Other, "Signer does not match the feed's key.")),
+                Some(_)                         => (),
+                None                            => try!(storage.put_key(key)),
+            }
+        } else if let Some((ref reader_key, _)) = reader {
+            key = *reader_key;
+            match state.key {
+                Some(stored) if stored != key   => return Err(io::Error::new(io::ErrorKind::Other, "Verifier does not match the feed's key.")),
+                Some(_)                         => (),
+                None                            => try!(storage.put_key(key)),
+            }
+        } else if let Some(stored) = state.key {
+            key = stored;
+            if let Some(candidate) = state_secret {
+                if keypair_matches(&key, &candidate) {
+                    secret = Some(candidate);
                 }
+            }
+        } else {
+            let pair: Keypair = match pair {
+                Some(pair)  => pair,
+                None        => {
+                    let mut cspring: OsRng = try!(OsRng::new());
+                    Keypair::generate::<Sha512>(&mut cspring)
+                },
             };
-        }
-
-        if generate_key {
-            let mut cspring: OsRng = try!(OsRng::new());
-            let pair: Keypair = Keypair::generate::<Sha512>(&mut cspring);
+            let mut generated = [0u8; 64];
             key = pair.public.to_bytes();
-            secret[32..].copy_from_slice(&key);
-            secret[..32].copy_from_slice(&pair.secret.to_bytes());
+            generated[32..].copy_from_slice(&key);
+            generated[..32].copy_from_slice(&pair.secret.to_bytes());
             try!(storage.put_key(key));
-            try!(storage.put_secret(secret));
+            match keystore {
+                Some(ref mut store) => try!(store.put_secret(&key, generated)),
+                None                => try!(storage.put_secret(generated)),
+            }
+            secret = Some(generated);
         }
 
+        if let Some(secret) = secret {
+            signer = Some(Box::new(try!(Ed25519Signer::new(&key, &secret))));
+        }
+        let verifier = match (&signer, reader) {
+            (&Some(ref signer), _)      => signer.verifier(),
+            (&None, Some((_, reader)))  => reader,
+            (&None, None)               => Box::new(try!(Ed25519Verifier::new(&key))),
+        };
+
+        try!(storage.index_offsets(blocks));
+
         let roots = try!(storage.get_roots(blocks));
         let merkle = Tree::with_roots(roots.clone());
         let length = roots.into_iter().fold(0, |sum, root| root.length + sum);
@@ -72,20 +248,81 @@ impl<T: Storage> Hypercore<T> {
             length:     length,
             key:        key,
             secret:     secret,
+            signer:     signer,
+            verifier:   verifier,
             merkle:     merkle,
             bitfield:   bitfield,
+            hash:       PhantomData,
         })
     }
 
-    pub fn has(&self, index: u64) -> bool {
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    pub fn writable(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    pub fn export_keypair(&self) -> Option<([u8; 32], [u8; 64])> {
+        self.secret.map(|secret| (self.key, secret))
+    }
+
+    // Makes this feed writable with a keypair taken from another machine.
+    // The secret has to belong to this feed's key and the key has to verify
+    // the latest root signature already in storage.
+    pub fn import_keypair(&mut self, key: [u8; 32], secret: [u8; 64], keystore: Option<&mut KeyStore>) -> Result<()> {
+        if !keypair_matches(&key, &secret) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Secret does not match public key."));
+        }
+
+        if key != self.key {
+            return Err(io::Error::new(io::ErrorKind::Other, "Keypair belongs to a different feed."));
+        }
+
+        let signer = try!(Ed25519Signer::new(&key, &secret));
+        if signer.scheme() != self.verifier.scheme() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Keypair does not match the feed's signature scheme."));
+        }
+
+        if self.blocks > 0 && !try!(self.verify_roots()) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Existing signatures do not verify."));
+        }
+
+        match keystore {
+            Some(store) => try!(store.put_secret(&key, secret)),
+            None        => try!(self.storage.put_secret(secret)),
+        }
+        self.secret = Some(secret);
+        self.signer = Some(Box::new(signer));
+        Ok(())
+    }
+
+    pub fn has(&self, index: u64) -> Result<bool> {
         self.bitfield.get(index)
     }
 
-    pub fn has_range(&self, range: Range<u64>) -> bool {
-        range.end - range.start == self.bitfield.total(range)
+    // How many blocks from the start we have without a gap, e.g. how far
+    // sequential playback can go. Worked out from the bitfield's index when
+    // asked, so opening a paged feed doesn't read every page.
+    pub fn contiguous_length(&self) -> Result<u64> {
+        Ok(try!(self.bitfield.find_first(false, 0..self.blocks)).unwrap_or(self.blocks))
+    }
+
+    // Drops local copies of blocks in `range`. The feed's length and the
+    // tree are unchanged, so the blocks can be fetched again.
+    pub fn clear(&mut self, range: Range<u64>) -> Result<()> {
+        if !try!(self.bitfield.clear(range)) {
+            return Ok(());
+        }
+        self.flush_bitfield()
+    }
+
+    pub fn has_range(&self, range: Range<u64>) -> Result<bool> {
+        Ok(range.end - range.start == try!(self.bitfield.total(range)))
     }
 
-    pub fn downloaded(&self, range: Range<u64>) -> u64 {
+    pub fn downloaded(&self, range: Range<u64>) -> Result<u64> {
         self.bitfield.total(range)
     }
 
@@ -99,13 +336,45 @@ impl<T: Storage> Hypercore<T> {
     //     }
     // }
 
-        pub fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
-            if !self.bitfield.get(index) {
+        pub fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
+            if !try!(self.bitfield.get(index)) {
                 return Err(io::Error::new(io::ErrorKind::Other, "Index not found."));
             }
             self.storage.get_data(index * 2)
         }
 
+    // Sent as `nodes` in a `Request` for `index`, so the proof we get back
+    // leaves out the tree nodes we already have.
+    pub fn digest(&self, index: u64) -> Result<u64> {
+        self.bitfield.digest(2 * index)
+    }
+
+    // Answers a `Request` for `index` carrying `digest`.
+    pub fn proof(&self, index: u64, digest: u64) -> Result<Proof> {
+        let mut opts = ProofOpts::new();
+        opts.set_digest(digest);
+
+        let (indexes, verified_by) = match try!(self.bitfield.proof(2 * index, opts)) {
+            Some(proof) => proof,
+            None        => return Err(io::Error::new(io::ErrorKind::Other, "Index not found.")),
+        };
+
+        let mut nodes = Vec::with_capacity(indexes.len());
+        for node in indexes {
+            match try!(self.storage.get_node(node)) {
+                Some(node)  => nodes.push(node),
+                None        => return Err(io::Error::new(io::ErrorKind::Other, "Unable to load node.")),
+            }
+        }
+
+        let signature = match verified_by {
+            0   => None,
+            _   => try!(self.storage.get_signature(verified_by / 2)),
+        };
+
+        Ok(Proof { index: index, nodes: nodes, signature: signature })
+    }
+
     // pub fn head(&mut self) -> DataFuture {
     //     let len = self.length;
     //     if len == 0 { return future::ok(None); }
@@ -120,29 +389,51 @@ impl<T: Storage> Hypercore<T> {
 
     // }
 
+    // Hashed with the tree's own hash, so a SHA-256 feed signs a SHA-256
+    // digest of its roots.
+    fn hash_roots(&self) -> Vec<u8> {
+        let encoded: Vec<(Vec<u8>, Vec<u8>)> = self.merkle.roots.iter()
+            .map(|root| (encodebe(root.index), encodebe(root.length)))
+            .collect();
+
+        let mut parts: Vec<&[u8]> = vec![ROOT_TYPE];
+        for (root, &(ref index, ref length)) in self.merkle.roots.iter().zip(&encoded) {
+            parts.push(&root.hash);
+            parts.push(index);
+            parts.push(length);
+        }
+
+        H::digest(&parts).to_vec()
+    }
+
     fn sign_roots(&mut self) -> Result<()> {
-        let mut hasher = Blake2b::new();
-        hasher.input(ROOT_TYPE);
+        let signature = match self.signer {
+            Some(ref signer)    => try!(signer.sign(&self.hash_roots())),
+            None                => return Err(io::Error::new(io::ErrorKind::Other, "Feed is not writable.")),
+        };
 
-        for root in &self.merkle.roots {
-            hasher.input(&root.hash);
-            hasher.input(&encodebe(root.index));
-            hasher.input(&encodebe(root.length));
+        if signature.len() != 64 {
+            return Err(io::Error::new(io::ErrorKind::Other, "Unable to sign roots."));
         }
 
-        let signature = match Keypair::from_bytes(&[&self.secret[..32], &self.key[..]].concat()) {
-            Ok(pair)    => pair.sign::<Sha512>(&hasher.result()),
-            Err(_)      => return Err(io::Error::new(io::ErrorKind::Other, "Unable to sign roots.")),
-        };
+        self.storage.put_signature(self.blocks, signature)
+    }
 
-        self.storage.put_signature(self.blocks, signature.to_bytes().to_vec())
+    fn verify_roots(&self) -> Result<bool> {
+        match try!(self.storage.get_signature(self.blocks)) {
+            Some(bytes) => Ok(self.verifier.verify(&self.hash_roots(), &bytes)),
+            None        => Ok(false),
+        }
     }
 
     pub fn append(&mut self, data: Vec<u8>) -> Result<()> {
         let len = data.len();
         if len == 0 { return Ok(()); }
+        if self.signer.is_none() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Feed is not writable."));
+        }
 
-        let nodes = self.merkle.insert::<Blake2b>(data);
+        let nodes = self.merkle.insert::<H>(data);
 
         for node in nodes {
             if let Err(_) = self.storage.put_node(node.index, node.clone()) {
@@ -155,15 +446,31 @@ impl<T: Storage> Hypercore<T> {
             }
         }
 
-        self.bitfield.set(self.blocks, true);
-        while let Some((offset, data)) = self.bitfield.last_updated() {
-                try!(self.storage.put_bitfield(offset as u64, data));
-        }
+        try!(self.bitfield.set(self.blocks, true));
+        try!(self.flush_bitfield());
         self.length += len as u64;
         self.blocks += 1;
 
         self.sign_roots()
     }
+
+    fn flush_bitfield(&mut self) -> Result<()> {
+        while let Some((offset, data)) = self.bitfield.last_updated() {
+                try!(self.storage.put_bitfield(offset as u64, data));
+        }
+        Ok(())
+    }
+}
+
+fn keypair_matches(key: &[u8; 32], secret: &[u8; 64]) -> bool {
+    if secret[32..] != key[..] {
+        return false;
+    }
+
+    match SecretKey::from_bytes(&secret[..32]) {
+        Ok(secret)  => PublicKey::from_secret::<Sha512>(&secret).to_bytes() == *key,
+        Err(_)      => false,
+    }
 }
 
 fn encodebe(input: u64) -> Vec<u8> {
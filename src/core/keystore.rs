@@ -0,0 +1,97 @@
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+// Holds feed secrets apart from the feed's data, keyed by public key.
+pub trait KeyStore {
+    fn get_secret(&self, key: &[u8; 32]) -> Result<Option<[u8; 64]>>;
+    fn put_secret(&mut self, key: &[u8; 32], secret: [u8; 64]) -> Result<()>;
+}
+
+pub struct MemoryKeyStore {
+    secrets:    HashMap<[u8; 32], [u8; 64]>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore {
+            secrets:    HashMap::new(),
+        }
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn get_secret(&self, key: &[u8; 32]) -> Result<Option<[u8; 64]>> {
+        Ok(self.secrets.get(key).map(|secret| *secret))
+    }
+
+    fn put_secret(&mut self, key: &[u8; 32], secret: [u8; 64]) -> Result<()> {
+        self.secrets.insert(*key, secret);
+        Ok(())
+    }
+}
+
+// One `<hex public key>.secret_key` file per feed, readable only by the
+// owner, in a directory that can live and be backed up apart from the data.
+pub struct FileKeyStore {
+    dir:        PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(dir: &Path) -> Result<FileKeyStore> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        try!(builder.create(dir));
+
+        Ok(FileKeyStore {
+            dir:        dir.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self, key: &[u8; 32]) -> PathBuf {
+        let name: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(format!("{}.secret_key", name))
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn get_secret(&self, key: &[u8; 32]) -> Result<Option<[u8; 64]>> {
+        // Only a missing file means there's no secret; anything else would
+        // quietly open a writable feed read-only.
+        let mut file = match File::open(self.path(key)) {
+            Ok(file)                                            => file,
+            Err(ref err) if err.kind() == ErrorKind::NotFound   => return Ok(None),
+            Err(err)                                            => return Err(err),
+        };
+
+        let mut buf = Vec::with_capacity(64);
+        try!(file.read_to_end(&mut buf));
+
+        if buf.len() != 64 {
+            return Err(Error::new(ErrorKind::Other, "Secret key file is corrupt."));
+        }
+
+        let mut secret = [0u8; 64];
+        secret.copy_from_slice(&buf);
+        Ok(Some(secret))
+    }
+
+    fn put_secret(&mut self, key: &[u8; 32], secret: [u8; 64]) -> Result<()> {
+        let path = self.path(key);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = try!(options.open(&path));
+        #[cfg(unix)]
+        try!(fs::set_permissions(&path, fs::Permissions::from_mode(0o600)));
+
+        file.write_all(&secret)
+    }
+}
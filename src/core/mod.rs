@@ -1,5 +1,10 @@
 pub mod storage;
 pub mod bitfield;
 pub mod hypercore;
+pub mod shared;
+pub mod keystore;
+pub mod signer;
+pub mod remote;
 
-pub use self::hypercore::Hypercore;
\ No newline at end of file
+pub use self::hypercore::{Hypercore, HypercoreBuilder, Proof};
+pub use self::shared::SharedHypercore;
@@ -0,0 +1,113 @@
+use std::cmp;
+use std::io::{Result, Error, ErrorKind};
+use std::ops::{Range};
+
+use common::rle;
+use common::sparse::SparseBitfield;
+use core::bitfield::Bitfield;
+
+// How many blocks a peer can claim by default: 2^30 blocks keep at most
+// 128 MiB of bitfield.
+const MAX_LENGTH: u64 = 1 << 30;
+
+// What one peer has told us it has, built up from its `Have` and `Unhave`
+// messages.
+pub struct RemoteBitfield {
+    data:       SparseBitfield,
+    max_length: u64,
+}
+
+impl RemoteBitfield {
+    pub fn new() -> RemoteBitfield {
+        RemoteBitfield::with_max_length(MAX_LENGTH)
+    }
+
+    // Messages about blocks from `max_length` on are rejected, so a peer
+    // can't make us hold more than `max_length / 8` bytes.
+    pub fn with_max_length(max_length: u64) -> RemoteBitfield {
+        RemoteBitfield {
+            data:       SparseBitfield::new(),
+            max_length: max_length,
+        }
+    }
+
+    pub fn get(&self, index: u64) -> Result<bool> {
+        self.data.get(index)
+    }
+
+    pub fn data(&self) -> &SparseBitfield {
+        &self.data
+    }
+
+    // Blocks from `max_length` on are never held, so the range is cut off
+    // there.
+    pub fn set_range(&mut self, range: Range<u64>, value: bool) -> Result<()> {
+        let fill = if value { 255 } else { 0 };
+        let end = cmp::min(range.end, self.max_length);
+        let mut index = range.start;
+
+        while index < end {
+            if index & 7 == 0 && index + 8 <= end {
+                try!(self.data.set_byte(index, fill));
+                index += 8;
+            } else {
+                try!(self.data.set(index, value));
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // A `Have` either covers `length` blocks from `start` or carries an RLE
+    // bitfield, which replaces what we knew from the byte holding `start`.
+    pub fn have(&mut self, start: u64, length: u64, bitfield: Option<&[u8]>) -> Result<()> {
+        let first = start & !7;
+        if first >= self.max_length {
+            return Err(Error::new(ErrorKind::Other, "Have is out of range."));
+        }
+
+        let buf = match bitfield {
+            Some(buf)   => buf,
+            None        => return self.set_range(try!(message_range(start, length)), true),
+        };
+
+        let max_len = (self.max_length - first - 1) / 8 + 1;
+        let bytes = try!(rle::decode(buf, cmp::min(max_len, usize::max_value() as u64) as usize));
+        for (i, &byte) in bytes.iter().enumerate() {
+            try!(self.data.set_byte(first + 8 * i as u64, rle::swap_bit_order(byte)));
+        }
+
+        Ok(())
+    }
+
+    pub fn unhave(&mut self, start: u64, length: u64) -> Result<()> {
+        self.set_range(try!(message_range(start, length)), false)
+    }
+
+    // The first block in `range` the peer has and `local` doesn't, i.e. the
+    // next one worth requesting from them. Each side skips ahead to the
+    // other's next candidate, so long runs are passed over by their indexes.
+    pub fn first_wanted(&self, local: &Bitfield, range: Range<u64>) -> Result<Option<u64>> {
+        let mut index = range.start;
+
+        loop {
+            let theirs = match try!(self.data.find_first(true, index..range.end)) {
+                Some(theirs)    => theirs,
+                None            => return Ok(None),
+            };
+
+            index = match try!(local.find_first(false, theirs..range.end)) {
+                Some(missing) if missing == theirs  => return Ok(Some(theirs)),
+                Some(missing)                       => missing,
+                None                                => return Ok(None),
+            };
+        }
+    }
+}
+
+fn message_range(start: u64, length: u64) -> Result<Range<u64>> {
+    match start.checked_add(length) {
+        Some(end)   => Ok(start..end),
+        None        => Err(Error::new(ErrorKind::Other, "Message range overflows.")),
+    }
+}
@@ -0,0 +1,69 @@
+use std::io::Result;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use blake2::Blake2b;
+
+use common::merkle::TreeHash;
+use core::hypercore::{Hypercore, HypercoreBuilder};
+use core::storage::Storage;
+
+// A cloneable handle to one feed. Reads share the lock, so many threads can
+// `get` at once, while `append` takes it exclusively.
+pub struct SharedHypercore<T: Storage, H: TreeHash = Blake2b> {
+    inner:      Arc<RwLock<Hypercore<T, H>>>,
+}
+
+impl<T: Storage> SharedHypercore<T> {
+    pub fn new(storage: T) -> Result<SharedHypercore<T>> {
+        SharedHypercore::with_builder(storage, HypercoreBuilder::new())
+    }
+}
+
+impl<T: Storage, H: TreeHash> SharedHypercore<T, H> {
+    pub fn with_builder(storage: T, builder: HypercoreBuilder<H>) -> Result<SharedHypercore<T, H>> {
+        Ok(SharedHypercore::from_hypercore(try!(builder.build(storage))))
+    }
+
+    pub fn from_hypercore(feed: Hypercore<T, H>) -> SharedHypercore<T, H> {
+        SharedHypercore {
+            inner:      Arc::new(RwLock::new(feed)),
+        }
+    }
+
+    pub fn has(&self, index: u64) -> Result<bool> {
+        self.inner.read().unwrap().has(index)
+    }
+
+    pub fn has_range(&self, range: Range<u64>) -> Result<bool> {
+        self.inner.read().unwrap().has_range(range)
+    }
+
+    pub fn downloaded(&self, range: Range<u64>) -> Result<u64> {
+        self.inner.read().unwrap().downloaded(range)
+    }
+
+    pub fn contiguous_length(&self) -> Result<u64> {
+        self.inner.read().unwrap().contiguous_length()
+    }
+
+    pub fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        self.inner.read().unwrap().get(index)
+    }
+
+    pub fn append(&self, data: Vec<u8>) -> Result<()> {
+        self.inner.write().unwrap().append(data)
+    }
+
+    pub fn clear(&self, range: Range<u64>) -> Result<()> {
+        self.inner.write().unwrap().clear(range)
+    }
+}
+
+impl<T: Storage, H: TreeHash> Clone for SharedHypercore<T, H> {
+    fn clone(&self) -> SharedHypercore<T, H> {
+        SharedHypercore {
+            inner:      Arc::clone(&self.inner),
+        }
+    }
+}
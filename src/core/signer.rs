@@ -0,0 +1,81 @@
+use std::io;
+use std::io::Result;
+
+use sha2::Sha512;
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+
+// Checks root signatures. `scheme` is written into the signatures file's
+// header, so a feed is always reopened with the scheme it was signed with.
+pub trait Verifier {
+    fn scheme(&self) -> &str;
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+// Signs roots for a writable feed. The secret never has to be in this
+// process, e.g. an implementation can forward to a local signing service.
+// Signatures are stored in 64 byte slots, so `sign` has to return exactly
+// 64 bytes; an append fails otherwise.
+pub trait Signer {
+    fn scheme(&self) -> &str;
+    fn public_key(&self) -> [u8; 32];
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+    fn verifier(&self) -> Box<Verifier + Send + Sync>;
+}
+
+pub struct Ed25519Verifier {
+    public:     PublicKey,
+}
+
+impl Ed25519Verifier {
+    pub fn new(key: &[u8; 32]) -> Result<Ed25519Verifier> {
+        match PublicKey::from_bytes(key) {
+            Ok(public)  => Ok(Ed25519Verifier { public: public }),
+            Err(_)      => Err(io::Error::new(io::ErrorKind::Other, "Invalid public key.")),
+        }
+    }
+}
+
+impl Verifier for Ed25519Verifier {
+    fn scheme(&self) -> &str {
+        "Ed25519"
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match Signature::from_bytes(signature) {
+            Ok(signature)   => self.public.verify::<Sha512>(message, &signature),
+            Err(_)          => false,
+        }
+    }
+}
+
+pub struct Ed25519Signer {
+    key:        [u8; 32],
+    pair:       Keypair,
+}
+
+impl Ed25519Signer {
+    pub fn new(key: &[u8; 32], secret: &[u8; 64]) -> Result<Ed25519Signer> {
+        match Keypair::from_bytes(&[&secret[..32], &key[..]].concat()) {
+            Ok(pair)    => Ok(Ed25519Signer { key: *key, pair: pair }),
+            Err(_)      => Err(io::Error::new(io::ErrorKind::Other, "Invalid keypair.")),
+        }
+    }
+}
+
+impl Signer for Ed25519Signer {
+    fn scheme(&self) -> &str {
+        "Ed25519"
+    }
+
+    fn public_key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.pair.sign::<Sha512>(message).to_bytes().to_vec())
+    }
+
+    fn verifier(&self) -> Box<Verifier + Send + Sync> {
+        Box::new(Ed25519Verifier { public: self.pair.public })
+    }
+}
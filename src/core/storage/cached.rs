@@ -1,43 +1,293 @@
-use std::io::Result;
+use std::io::{Result, Error, ErrorKind};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::usize;
 use lru_cache::LruCache;
 
 use core::storage::{Storage, FileType};
 use common::merkle::Node;
+use common::pager::PageSource;
+
+const NODE_CACHE_CAPACITY: usize = 65536;
+const DATA_CACHE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits:       u64,
+    pub misses:     u64,
+    pub evictions:  u64,
+}
+
+struct NodeCache {
+    nodes:          LruCache<u64, Node>,
+    stats:          CacheStats,
+}
+
+impl NodeCache {
+    fn new(capacity: usize) -> NodeCache {
+        NodeCache {
+            nodes:          LruCache::new(capacity),
+            stats:          CacheStats::default(),
+        }
+    }
+
+    fn get(&mut self, index: u64) -> Option<Node> {
+        match self.nodes.get_mut(&index) {
+            Some(node) => {
+                self.stats.hits += 1;
+                Some(node.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    fn insert(&mut self, index: u64, mut node: Node) {
+        if self.nodes.capacity() == 0 { return; }
+        if !self.nodes.contains_key(&index) && self.nodes.len() == self.nodes.capacity() {
+            self.stats.evictions += 1;
+        }
+
+        node.data = None;
+        self.nodes.insert(index, node);
+    }
+
+    fn remove(&mut self, index: u64) {
+        self.nodes.remove(&index);
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+    }
+}
+
+// Data blocks keyed by index, evicted by total size rather than count.
+// `offsets` orders cached blocks by byte offset so raw writes to the data
+// file can drop whatever they overlap. It holds the index too, as an empty
+// block starts where the next one does.
+struct BlockCache {
+    blocks:         LruCache<u64, (u64, Vec<u8>)>,
+    offsets:        BTreeSet<(u64, u64)>,
+    bytes:          usize,
+    max_bytes:      usize,
+    stats:          CacheStats,
+}
+
+impl BlockCache {
+    fn new(max_bytes: usize) -> BlockCache {
+        BlockCache {
+            blocks:         LruCache::new(usize::MAX),
+            offsets:        BTreeSet::new(),
+            bytes:          0,
+            max_bytes:      max_bytes,
+            stats:          CacheStats::default(),
+        }
+    }
+
+    fn get(&mut self, index: u64) -> Option<Vec<u8>> {
+        match self.blocks.get_mut(&index) {
+            Some(&mut (_, ref data)) => {
+                self.stats.hits += 1;
+                Some(data.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    fn insert(&mut self, index: u64, offset: u64, data: Vec<u8>) {
+        self.remove(index);
+        if data.len() > self.max_bytes { return; }
+
+        self.bytes += data.len();
+        self.offsets.insert((offset, index));
+        self.blocks.insert(index, (offset, data));
+
+        while self.bytes > self.max_bytes {
+            match self.blocks.remove_lru() {
+                Some((index, (offset, data))) => {
+                    self.bytes -= data.len();
+                    self.offsets.remove(&(offset, index));
+                    self.stats.evictions += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, index: u64) {
+        if let Some((offset, data)) = self.blocks.remove(&index) {
+            self.bytes -= data.len();
+            self.offsets.remove(&(offset, index));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.offsets.clear();
+        self.bytes = 0;
+    }
+
+    fn invalidate(&mut self, offset: u64, len: u64) {
+        let mut stale: Vec<u64> = Vec::new();
+
+        for &(start, index) in self.offsets.range(..(offset + len, 0)).rev() {
+            let end = match self.blocks.get_mut(&index) {
+                Some(&mut (_, ref data)) => start + data.len() as u64,
+                None => continue,
+            };
+            if end <= offset { break; }
+            stale.push(index);
+        }
+
+        for index in stale {
+            self.remove(index);
+        }
+    }
+}
+
+pub struct CachedStorageBuilder {
+    node_capacity:  usize,
+    data_bytes:     usize,
+}
+
+impl CachedStorageBuilder {
+    pub fn new() -> CachedStorageBuilder {
+        CachedStorageBuilder {
+            node_capacity:  NODE_CACHE_CAPACITY,
+            data_bytes:     DATA_CACHE_BYTES,
+        }
+    }
+
+    pub fn node_capacity(mut self, capacity: usize) -> CachedStorageBuilder {
+        self.node_capacity = capacity;
+        self
+    }
+
+    pub fn data_bytes(mut self, max_bytes: usize) -> CachedStorageBuilder {
+        self.data_bytes = max_bytes;
+        self
+    }
+
+    pub fn build<T: Storage>(self, storage: T) -> CachedStorage<T> {
+        CachedStorage {
+            nodes:          Mutex::new(NodeCache::new(self.node_capacity)),
+            data:           Mutex::new(BlockCache::new(self.data_bytes)),
+            storage:        storage,
+        }
+    }
+}
 
 pub struct CachedStorage<T: Storage> {
-    cache:          LruCache<u64, Node>,
+    nodes:          Mutex<NodeCache>,
+    data:           Mutex<BlockCache>,
     storage:        T
 }
 
 impl<T: Storage> CachedStorage<T> {
     pub fn new(storage: T) -> CachedStorage<T> {
-        CachedStorage{
-            cache:          LruCache::new(65536),
-            storage:        storage,
-        }
+        CachedStorageBuilder::new().build(storage)
+    }
+
+    pub fn node_stats(&self) -> CacheStats {
+        self.nodes.lock().unwrap().stats
+    }
+
+    pub fn data_stats(&self) -> CacheStats {
+        self.data.lock().unwrap().stats
+    }
+
+    pub fn invalidate_node(&self, index: u64) {
+        self.nodes.lock().unwrap().remove(index);
+    }
+
+    pub fn invalidate_data(&self, index: u64) {
+        self.data.lock().unwrap().remove(index);
+    }
+
+    pub fn clear(&self) {
+        self.nodes.lock().unwrap().clear();
+        self.data.lock().unwrap().clear();
     }
 }
 
 impl<T: Storage> Storage for CachedStorage<T> {
-    fn read_archive(&mut self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
         self.storage.read_archive(file_type, offset, buf)
     }
 
     fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()> {
+        match file_type {
+            FileType::Data  => self.data.lock().unwrap().invalidate(offset, buf.len() as u64),
+            FileType::Tree  => {
+                let mut nodes = self.nodes.lock().unwrap();
+                let start = offset.saturating_sub(32) / 40;
+                let end = (offset + buf.len() as u64).saturating_sub(32 + 1) / 40;
+                for index in start..(end + 1) {
+                    nodes.remove(index);
+                }
+            },
+            _               => {},
+        }
         self.storage.write_archive(file_type, offset, buf)
     }
 
-    fn get_node(&mut self, index: u64) -> Result<Option<Node>> {
-        if let Some(node) = self.cache.get_mut(&index) {
-            return Ok(Some(node.clone()));
+    fn page_source(&self) -> Result<Option<Box<PageSource>>> {
+        self.storage.page_source()
+    }
+
+    fn get_node(&self, index: u64) -> Result<Option<Node>> {
+        if let Some(node) = self.nodes.lock().unwrap().get(index) {
+            return Ok(Some(node));
         }
 
         match try!(self.storage.get_node(index)) {
             None        => Ok(None),
             Some(node)  => {
-                self.cache.insert(index, node.clone());
+                self.nodes.lock().unwrap().insert(index, node.clone());
                 Ok(Some(node))
             },
         }
     }
-} 
\ No newline at end of file
+
+    fn put_node(&mut self, index: u64, node: Node) -> Result<()> {
+        try!(self.storage.put_node(index, node.clone()));
+        self.nodes.lock().unwrap().insert(index, node);
+        Ok(())
+    }
+
+    fn get_data(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.data.lock().unwrap().get(index) {
+            return Ok(Some(data));
+        }
+
+        if let Some((offset, size)) = try!(self.get_offset(index)) {
+            let mut buf: Vec<u8> = vec![0u8; size as usize];
+
+            try!(self.storage.read_archive(FileType::Data, offset, &mut buf));
+            self.data.lock().unwrap().insert(index, offset, buf.clone());
+
+            return Ok(Some(buf));
+        }
+
+        Ok(None)
+    }
+
+    fn put_data(&mut self, index: u64, data: Vec<u8>) -> Result<()> {
+        if let Some((offset, size)) = try!(self.get_offset(index)) {
+            if data.len() != size as usize {
+                return Err(Error::new(ErrorKind::Other, "Unexpected data size."));
+            }
+
+            try!(self.write_archive(FileType::Data, offset, &data));
+            try!(self.put_offset(index, offset, size));
+            self.data.lock().unwrap().insert(index, offset, data);
+        }
+
+        Ok(())
+    }
+}
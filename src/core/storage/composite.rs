@@ -0,0 +1,72 @@
+use std::io::Result;
+
+use core::storage::{Storage, FileType, RandomAccess};
+
+pub type Backend = Box<RandomAccess + Send + Sync>;
+
+// Routes each `FileType` to its own backend, e.g. keeping the data on a
+// large slow disk, the tree and bitfield on SSD and the secret key in
+// memory or a separate keystore.
+pub struct CompositeStorage {
+    tree:           Backend,
+    signatures:     Backend,
+    bitfield:       Backend,
+    key:            Backend,
+    secret:         Backend,
+    data:           Backend,
+    offsets:        Backend,
+}
+
+impl CompositeStorage {
+    pub fn new<F>(mut open: F) -> Result<CompositeStorage>
+                  where F: FnMut(FileType) -> Result<Backend> {
+        Ok(CompositeStorage {
+            tree:           try!(open(FileType::Tree)),
+            signatures:     try!(open(FileType::Signatures)),
+            bitfield:       try!(open(FileType::Bitfield)),
+            key:            try!(open(FileType::Key)),
+            secret:         try!(open(FileType::Secret)),
+            data:           try!(open(FileType::Data)),
+            offsets:        try!(open(FileType::Offsets)),
+        })
+    }
+
+    pub fn route(mut self, file_type: FileType, backend: Backend) -> CompositeStorage {
+        *self.get_file_mut(file_type) = backend;
+        self
+    }
+
+    fn get_file(&self, file_type: FileType) -> &Backend {
+        match file_type {
+            FileType::Tree         => &self.tree,
+            FileType::Signatures   => &self.signatures,
+            FileType::Bitfield     => &self.bitfield,
+            FileType::Key          => &self.key,
+            FileType::Secret       => &self.secret,
+            FileType::Data         => &self.data,
+            FileType::Offsets      => &self.offsets,
+        }
+    }
+
+    fn get_file_mut(&mut self, file_type: FileType) -> &mut Backend {
+        match file_type {
+            FileType::Tree         => &mut self.tree,
+            FileType::Signatures   => &mut self.signatures,
+            FileType::Bitfield     => &mut self.bitfield,
+            FileType::Key          => &mut self.key,
+            FileType::Secret       => &mut self.secret,
+            FileType::Data         => &mut self.data,
+            FileType::Offsets      => &mut self.offsets,
+        }
+    }
+}
+
+impl Storage for CompositeStorage {
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.get_file(file_type).read(offset, buf)
+    }
+
+    fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()> {
+        self.get_file_mut(file_type).write(offset, buf)
+    }
+}
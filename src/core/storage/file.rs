@@ -1,8 +1,13 @@
-use std::io::{Result, Error, ErrorKind, Write, Read, Seek, SeekFrom};
+use std::io::{Result, Error, ErrorKind};
 use std::fs::{File, OpenOptions, create_dir};
-use std::path::{Path};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
 
-use core::storage::{Storage, FileType};
+use common::pager::PageSource;
+use core::storage::{Storage, FileType, Layout};
 
 pub struct FileStorage {
     tree:           File,
@@ -11,69 +16,136 @@ pub struct FileStorage {
     key:            File,
     secret:         File,
     data:           File,
+    offsets:        File,
 }
 
 impl FileStorage {
     pub fn new(path: &Path) -> Result<FileStorage> {
-        if !path.is_dir() {
-            return Err(Error::new(ErrorKind::Other, "Path is not a directory"));
-        }
+        FileStorage::with_layout(&Layout::new(&try!(dat_dir(path))))
+    }
 
-        let path = &path.join(".dat");
-        if let Err(err) = create_dir(&path) {
-            if err.kind() != ErrorKind::AlreadyExists {
-                return Err(err);
-            }
-        };
+    pub fn with_layout(layout: &Layout) -> Result<FileStorage> {
+        try!(layout.create_dir());
 
         Ok(FileStorage {
-            tree:           try!(open_or_create(path, FileType::Tree)),
-            signatures:     try!(open_or_create(path, FileType::Signatures)),
-            bitfield:       try!(open_or_create(path, FileType::Bitfield)),
-            key:            try!(open_or_create(path, FileType::Key)),
-            secret:         try!(open_or_create(path, FileType::Secret)),
-            data:           try!(open_or_create(path, FileType::Data)),
+            tree:           try!(open_or_create(&layout.path(FileType::Tree))),
+            signatures:     try!(open_or_create(&layout.path(FileType::Signatures))),
+            bitfield:       try!(open_or_create(&layout.path(FileType::Bitfield))),
+            key:            try!(open_or_create(&layout.path(FileType::Key))),
+            secret:         try!(open_or_create(&layout.path(FileType::Secret))),
+            data:           try!(open_or_create(&layout.path(FileType::Data))),
+            offsets:        try!(open_or_create(&layout.path(FileType::Offsets))),
         })
     }
 
-    fn get_file(&mut self, file_type: FileType) -> &mut File {
+    fn get_file(&self, file_type: FileType) -> &File {
         match file_type {
-            FileType::Tree         => &mut self.tree,
-            FileType::Signatures   => &mut self.signatures,
-            FileType::Bitfield     => &mut self.bitfield,
-            FileType::Key          => &mut self.key,
-            FileType::Secret       => &mut self.secret,
-            FileType::Data         => &mut self.data,
+            FileType::Tree         => &self.tree,
+            FileType::Signatures   => &self.signatures,
+            FileType::Bitfield     => &self.bitfield,
+            FileType::Key          => &self.key,
+            FileType::Secret       => &self.secret,
+            FileType::Data         => &self.data,
+            FileType::Offsets      => &self.offsets,
         }
     }
 }
 
 impl Storage for FileStorage {
-    fn read_archive(&mut self, file_type: FileType, offset: u64, mut buf: &mut [u8]) -> Result<usize> {
-        let file = self.get_file(file_type);
-        try!(file.seek(SeekFrom::Start(offset)));
-        file.read(&mut buf)
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        read_at(self.get_file(file_type), offset, buf)
     }
 
     fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()> {
-        let file = self.get_file(file_type);
-        try!(file.seek(SeekFrom::Start(offset)));
-        file.write_all(&buf)
+        write_all_at(self.get_file(file_type), offset, buf)
+    }
+
+    fn page_source(&self) -> Result<Option<Box<PageSource>>> {
+        Ok(Some(Box::new(BitfieldFile { file: try!(self.bitfield.try_clone()) })))
     }
 }
 
-fn open_or_create(path: &Path, file_type: FileType) -> Result<File> {
-    let filename = match file_type {
-            FileType::Tree         => "metadata.tree",
-            FileType::Signatures   => "metadata.signatures",
-            FileType::Bitfield     => "metadata.bitfield",
-            FileType::Key          => "metadata.key",
-            FileType::Secret       => "metadata.secret_key",
-            FileType::Data         => "metadata.data",
+// Bitfield pages follow the 32 byte header, one every 3328 bytes.
+struct BitfieldFile {
+    file:           File,
+}
+
+impl PageSource for BitfieldFile {
+    fn pages(&self) -> Result<usize> {
+        let len = try!(self.file.metadata()).len();
+        Ok(((len.saturating_sub(32) + 3327) / 3328) as usize)
+    }
+
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<usize> {
+        let offset = 32 + 3328 * index as u64;
+        let mut total = 0;
+
+        while total < buf.len() {
+            match try!(read_at(&self.file, offset + total as u64, &mut buf[total..])) {
+                0   => break,
+                n   => total += n,
+            }
+        }
+
+        Ok(total)
+    }
+}
+
+pub fn dat_dir(path: &Path) -> Result<PathBuf> {
+    if !path.is_dir() {
+        return Err(Error::new(ErrorKind::Other, "Path is not a directory"));
+    }
+
+    let path = path.join(".dat");
+    if let Err(err) = create_dir(&path) {
+        if err.kind() != ErrorKind::AlreadyExists {
+            return Err(err);
+        }
     };
 
-    match OpenOptions::new().read(true).write(true).open(path.join(filename)) {
+    Ok(path)
+}
+
+pub fn open_or_create(path: &Path) -> Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
         Ok(file)    => Ok(file),
-        Err(_)      => OpenOptions::new().create(true).read(true).write(true).open(path.join(filename))
+        Err(_)      => OpenOptions::new().create(true).read(true).write(true).open(path)
     }
-}
\ No newline at end of file
+}
+
+// Positional reads and writes don't move a shared cursor, so a `File` can
+// be read through `&self` from several threads at once.
+#[cfg(unix)]
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<usize> {
+    FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, offset: u64, buf: &[u8]) -> Result<usize> {
+    FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, offset: u64, buf: &[u8]) -> Result<usize> {
+    FileExt::seek_write(file, buf, offset)
+}
+
+pub fn write_all_at(file: &File, mut offset: u64, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match write_at(file, offset, buf) {
+            Ok(0)       => return Err(Error::new(ErrorKind::WriteZero, "Failed to write whole buffer.")),
+            Ok(n)       => {
+                offset += n as u64;
+                buf = &buf[n..];
+            },
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err)    => return Err(err),
+        }
+    }
+    Ok(())
+}
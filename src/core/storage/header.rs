@@ -0,0 +1,108 @@
+use std::io::{Result, Error, ErrorKind};
+
+use core::storage::FileType;
+
+pub const HEADER_SIZE: usize = 32;
+pub const HEADER_VERSION: u8 = 0;
+// Bitfields from version 1 on have an index that can be trusted; older ones
+// are reindexed when they're set up.
+pub const BITFIELD_VERSION: u8 = 1;
+
+const MAGIC: [u8; 3] = [5u8, 2u8, 87u8];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHeader {
+    pub magic:      u8,
+    pub version:    u8,
+    pub entry_size: u16,
+    pub algorithm:  String,
+}
+
+impl FileHeader {
+    pub fn new(file_type: FileType) -> Option<FileHeader> {
+        let (magic, version, entry_size, algorithm) = match file_type {
+            FileType::Tree          => (2u8, HEADER_VERSION, 40u16, "BLAKE2b"),
+            FileType::Signatures    => (1u8, HEADER_VERSION, 64u16, "Ed25519"),
+            FileType::Bitfield      => (0u8, BITFIELD_VERSION, 3328u16, ""),
+            _                       => return None,
+        };
+
+        Some(FileHeader {
+            magic:      magic,
+            version:    version,
+            entry_size: entry_size,
+            algorithm:  algorithm.to_string(),
+        })
+    }
+
+    // The name has to fit in the header after its length byte.
+    pub fn with_algorithm(mut self, algorithm: &str) -> Result<FileHeader> {
+        if 8 + algorithm.len() > HEADER_SIZE {
+            return Err(Error::new(ErrorKind::Other, "Header algorithm name is too long."));
+        }
+
+        self.algorithm = algorithm.to_string();
+        Ok(self)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<FileHeader> {
+        if buf.len() < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::Other, "Header is truncated."));
+        }
+
+        if buf[..3] != MAGIC {
+            return Err(Error::new(ErrorKind::Other, "Header has an unknown magic number."));
+        }
+
+        let name_len = buf[7] as usize;
+        if 8 + name_len > HEADER_SIZE {
+            return Err(Error::new(ErrorKind::Other, "Header algorithm name is too long."));
+        }
+
+        let algorithm = match String::from_utf8(buf[8..(8 + name_len)].to_vec()) {
+            Ok(name)    => name,
+            Err(_)      => return Err(Error::new(ErrorKind::Other, "Header algorithm name is not valid UTF-8.")),
+        };
+
+        Ok(FileHeader {
+            magic:      buf[3],
+            version:    buf[4],
+            entry_size: (buf[5] as u16) << 8 | buf[6] as u16,
+            algorithm:  algorithm,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut result = [0u8; HEADER_SIZE];
+        let name = self.algorithm.as_bytes();
+
+        result[..3].copy_from_slice(&MAGIC);
+        result[3] = self.magic;
+
+        result[4] = self.version;
+
+        result[5] = (self.entry_size >> 8) as u8;
+        result[6] = self.entry_size as u8;
+
+        result[7] = name.len() as u8;
+        result[8..(8 + name.len())].copy_from_slice(name);
+
+        result
+    }
+
+    pub fn validate(&self, expected: &FileHeader) -> Result<()> {
+        if self.magic != expected.magic {
+            return Err(Error::new(ErrorKind::Other, "Header belongs to a different file type."));
+        }
+
+        if self.entry_size != expected.entry_size {
+            return Err(Error::new(ErrorKind::Other, "Header entry size does not match."));
+        }
+
+        if self.algorithm != expected.algorithm {
+            return Err(Error::new(ErrorKind::Other, "Header algorithm does not match."));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::Result;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+use core::storage::FileType;
+
+// Where a feed's files live and what they're called, so several feeds can
+// share a directory, e.g. `Layout::new(dir).prefix("content.")` next to the
+// default metadata feed. Only the file names follow the JavaScript hypercore:
+// the file contents differ (bitfield bit order, signature slots and the root
+// hash encoding), so directories written by it can't be opened.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    dir:        PathBuf,
+    prefix:     String,
+}
+
+impl Layout {
+    pub fn new(dir: &Path) -> Layout {
+        Layout {
+            dir:        dir.to_path_buf(),
+            prefix:     "metadata.".to_string(),
+        }
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Layout {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn create_dir(&self) -> Result<()> {
+        create_dir_all(&self.dir)
+    }
+
+    pub fn path(&self, file_type: FileType) -> PathBuf {
+        let name = match file_type {
+            FileType::Tree         => "tree",
+            FileType::Signatures   => "signatures",
+            FileType::Bitfield     => "bitfield",
+            FileType::Key          => "key",
+            FileType::Secret       => "secret_key",
+            FileType::Data         => "data",
+            FileType::Offsets      => "offsets",
+        };
+
+        self.dir.join(format!("{}{}", self.prefix, name))
+    }
+}
@@ -1,29 +1,43 @@
-use std::io::{Result, Write, Read, Seek, SeekFrom, Cursor};
+use std::io::Result;
 
-use core::storage::{Storage, FileType};
+use core::storage::{Storage, FileType, RandomAccess};
 
 pub struct MemoryStorage {
-    tree:           Cursor<Vec<u8>>,
-    signatures:     Cursor<Vec<u8>>,
-    bitfield:       Cursor<Vec<u8>>,
-    key:            Cursor<Vec<u8>>,
-    secret:         Cursor<Vec<u8>>,
-    data:           Cursor<Vec<u8>>,
+    tree:           Vec<u8>,
+    signatures:     Vec<u8>,
+    bitfield:       Vec<u8>,
+    key:            Vec<u8>,
+    secret:         Vec<u8>,
+    data:           Vec<u8>,
+    offsets:        Vec<u8>,
 }
 
 impl MemoryStorage {
     pub fn new() -> MemoryStorage {
         MemoryStorage {
-            tree:           Cursor::new(Vec::with_capacity(1024)),
-            signatures:     Cursor::new(Vec::with_capacity(1024)),
-            bitfield:       Cursor::new(Vec::with_capacity(3328)),
-            key:            Cursor::new(Vec::with_capacity(32)),
-            secret:         Cursor::new(Vec::with_capacity(64)),
-            data:           Cursor::new(Vec::with_capacity(1024)),
+            tree:           Vec::with_capacity(1024),
+            signatures:     Vec::with_capacity(1024),
+            bitfield:       Vec::with_capacity(3328),
+            key:            Vec::with_capacity(32),
+            secret:         Vec::with_capacity(64),
+            data:           Vec::with_capacity(1024),
+            offsets:        Vec::with_capacity(1024),
         }
     }
 
-    fn get_file(&mut self, file_type: FileType) -> &mut Cursor<Vec<u8>> {
+    fn get_file(&self, file_type: FileType) -> &Vec<u8> {
+        match file_type {
+            FileType::Tree         => &self.tree,
+            FileType::Signatures   => &self.signatures,
+            FileType::Bitfield     => &self.bitfield,
+            FileType::Key          => &self.key,
+            FileType::Secret       => &self.secret,
+            FileType::Data         => &self.data,
+            FileType::Offsets      => &self.offsets,
+        }
+    }
+
+    fn get_file_mut(&mut self, file_type: FileType) -> &mut Vec<u8> {
         match file_type {
             FileType::Tree         => &mut self.tree,
             FileType::Signatures   => &mut self.signatures,
@@ -31,20 +45,17 @@ impl MemoryStorage {
             FileType::Key          => &mut self.key,
             FileType::Secret       => &mut self.secret,
             FileType::Data         => &mut self.data,
+            FileType::Offsets      => &mut self.offsets,
         }
     }
 }
 
 impl Storage for MemoryStorage {
-    fn read_archive(&mut self, file_type: FileType, offset: u64, mut buf: &mut [u8]) -> Result<usize> {
-        let file = self.get_file(file_type);
-        try!(file.seek(SeekFrom::Start(offset)));
-        file.read(&mut buf)
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.get_file(file_type).read(offset, buf)
     }
 
     fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()> {
-        let file = self.get_file(file_type);
-        try!(file.seek(SeekFrom::Start(offset)));
-        file.write_all(&buf)
+        self.get_file_mut(file_type).write(offset, buf)
     }
 }
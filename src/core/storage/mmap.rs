@@ -0,0 +1,193 @@
+use std::cmp;
+use std::io::Result;
+use std::fs::File;
+use std::path::Path;
+
+use memmap::MmapMut;
+
+use core::storage::{Storage, FileType, FileHeader, Layout, RandomAccess};
+use core::storage::file::{dat_dir, open_or_create};
+use core::storage::header::HEADER_SIZE;
+
+const MIN_CAPACITY: u64 = 64 * 1024;
+
+// A file mapped into memory. The mapping grows ahead of the data, so `len`
+// tracks the logical size and the file is trimmed back to it on drop. If
+// that never happened, e.g. after a crash, the zeros it was grown with are
+// told apart from entries on open.
+pub struct MappedFile {
+    file:       File,
+    map:        Option<MmapMut>,
+    len:        u64,
+}
+
+impl MappedFile {
+    pub fn open(file: File, file_type: FileType) -> Result<MappedFile> {
+        let capacity = try!(file.metadata()).len();
+        let mut mapped = MappedFile {
+            file:       file,
+            map:        None,
+            len:        0,
+        };
+
+        try!(mapped.remap(capacity));
+        mapped.len = mapped.logical_len(file_type);
+        Ok(mapped)
+    }
+
+    // The end of the last entry holding anything but zeros. An entry of
+    // zeros reads the same as a missing one, so dropping trailing ones
+    // loses nothing.
+    fn logical_len(&self, file_type: FileType) -> u64 {
+        let (header_size, entry_size) = match FileHeader::new(file_type) {
+            Some(header)    => (HEADER_SIZE as u64, header.entry_size as u64),
+            None            => (0, 8),
+        };
+
+        let end = match self.map {
+            Some(ref map)   => map.iter().rposition(|&byte| byte != 0).map_or(0, |last| last as u64 + 1),
+            None            => 0,
+        };
+
+        if end == 0 {
+            return 0;
+        }
+        if end <= header_size {
+            return header_size;
+        }
+
+        let entries = (end - header_size + entry_size - 1) / entry_size;
+        cmp::min(header_size + entries * entry_size, self.capacity())
+    }
+
+    fn remap(&mut self, capacity: u64) -> Result<()> {
+        if let Some(ref map) = self.map {
+            try!(map.flush());
+        }
+        self.map = None;
+
+        if capacity == 0 {
+            return Ok(());
+        }
+
+        try!(self.file.set_len(capacity));
+        self.map = Some(try!(unsafe { MmapMut::map_mut(&self.file) }));
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        match self.map {
+            Some(ref map)   => map.len() as u64,
+            None            => 0,
+        }
+    }
+
+}
+
+impl RandomAccess for MappedFile {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+
+        let start = offset as usize;
+        let end = cmp::min(self.len, offset + buf.len() as u64) as usize;
+        match self.map {
+            Some(ref map)   => {
+                buf[..(end - start)].copy_from_slice(&map[start..end]);
+                Ok(end - start)
+            },
+            None            => Ok(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let end = offset + buf.len() as u64;
+
+        if end > self.capacity() {
+            let capacity = cmp::max(cmp::max(end, self.capacity() * 2), MIN_CAPACITY);
+            try!(self.remap(capacity));
+        }
+
+        if let Some(ref mut map) = self.map {
+            map[(offset as usize)..(end as usize)].copy_from_slice(buf);
+        }
+
+        self.len = cmp::max(self.len, end);
+        Ok(())
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Some(ref map) = self.map {
+            let _ = map.flush();
+        }
+        self.map = None;
+        let _ = self.file.set_len(self.len);
+    }
+}
+
+pub struct MmapStorage {
+    tree:           MappedFile,
+    signatures:     MappedFile,
+    bitfield:       MappedFile,
+    offsets:        MappedFile,
+    key:            File,
+    secret:         File,
+    data:           File,
+}
+
+impl MmapStorage {
+    pub fn new(path: &Path) -> Result<MmapStorage> {
+        MmapStorage::with_layout(&Layout::new(&try!(dat_dir(path))))
+    }
+
+    pub fn with_layout(layout: &Layout) -> Result<MmapStorage> {
+        try!(layout.create_dir());
+
+        Ok(MmapStorage {
+            tree:           try!(MappedFile::open(try!(open_or_create(&layout.path(FileType::Tree))), FileType::Tree)),
+            signatures:     try!(MappedFile::open(try!(open_or_create(&layout.path(FileType::Signatures))), FileType::Signatures)),
+            bitfield:       try!(MappedFile::open(try!(open_or_create(&layout.path(FileType::Bitfield))), FileType::Bitfield)),
+            offsets:        try!(MappedFile::open(try!(open_or_create(&layout.path(FileType::Offsets))), FileType::Offsets)),
+            key:            try!(open_or_create(&layout.path(FileType::Key))),
+            secret:         try!(open_or_create(&layout.path(FileType::Secret))),
+            data:           try!(open_or_create(&layout.path(FileType::Data))),
+        })
+    }
+
+    fn get_file(&self, file_type: FileType) -> &RandomAccess {
+        match file_type {
+            FileType::Tree         => &self.tree,
+            FileType::Signatures   => &self.signatures,
+            FileType::Bitfield     => &self.bitfield,
+            FileType::Offsets      => &self.offsets,
+            FileType::Key          => &self.key,
+            FileType::Secret       => &self.secret,
+            FileType::Data         => &self.data,
+        }
+    }
+
+    fn get_file_mut(&mut self, file_type: FileType) -> &mut RandomAccess {
+        match file_type {
+            FileType::Tree         => &mut self.tree,
+            FileType::Signatures   => &mut self.signatures,
+            FileType::Bitfield     => &mut self.bitfield,
+            FileType::Offsets      => &mut self.offsets,
+            FileType::Key          => &mut self.key,
+            FileType::Secret       => &mut self.secret,
+            FileType::Data         => &mut self.data,
+        }
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.get_file(file_type).read(offset, buf)
+    }
+
+    fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()> {
+        self.get_file_mut(file_type).write(offset, buf)
+    }
+}
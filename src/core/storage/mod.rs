@@ -1,18 +1,37 @@
 use std::io::{Result, Error, ErrorKind};
 
+use futures::Future;
+
 use common::merkle::Node;
 use common::flat;
+use common::pager::PageSource;
+use core::bitfield::Bitfield;
 
 pub mod file;
 pub mod cached;
 pub mod memory;
+pub mod mmap;
+pub mod header;
+pub mod layout;
+pub mod random_access;
+pub mod composite;
+pub mod pool;
 
 pub use self::file::FileStorage;
-pub use self::cached::CachedStorage;
+pub use self::cached::{CachedStorage, CachedStorageBuilder, CacheStats};
 pub use self::memory::MemoryStorage;
+pub use self::mmap::{MmapStorage, MappedFile};
+pub use self::header::FileHeader;
+pub use self::layout::Layout;
+pub use self::random_access::RandomAccess;
+pub use self::composite::{CompositeStorage, Backend};
+pub use self::pool::PooledStorage;
+
+// How much of a bitfield is held while it's reindexed on upgrade.
+const REINDEX_BUDGET: usize = 1 << 20;
 
-#[derive(Clone, Copy, Debug)]
-pub enum FileType { Tree, Signatures, Bitfield, Key, Secret, Data }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType { Tree, Signatures, Bitfield, Key, Secret, Data, Offsets }
 
 pub struct StorageState {
     pub bitfield:       Vec<u8>,
@@ -20,20 +39,48 @@ pub struct StorageState {
     pub secret:         Option<[u8; 64]>,
 }
 
+pub type ArchiveFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+// Non-blocking counterpart of `Storage`. Buffers are owned so the work can
+// finish on another thread.
+pub trait AsyncStorage {
+    fn read_archive(&self, file_type: FileType, offset: u64, len: usize) -> ArchiveFuture<Vec<u8>>;
+    fn write_archive(&self, file_type: FileType, offset: u64, data: Vec<u8>) -> ArchiveFuture<()>;
+}
+
 pub trait Storage {
-    fn read_archive(&mut self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> Result<usize>;
     fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> Result<()>;
 
     fn setup(&mut self) -> Result<()> {
-        let file_types = [FileType::Tree, FileType::Signatures, FileType::Bitfield, FileType::Key, FileType::Secret, FileType::Data];
+        self.setup_with(&[])
+    }
+
+    // Like `setup`, but `overrides` replaces the default header expected for
+    // a file type, e.g. a tree header naming a hash other than BLAKE2b.
+    fn setup_with(&mut self, overrides: &[(FileType, FileHeader)]) -> Result<()> {
+        let file_types = [FileType::Tree, FileType::Signatures, FileType::Bitfield, FileType::Key, FileType::Secret, FileType::Data, FileType::Offsets];
 
         for &file_type in &file_types {
-            if let Some(header) = create_header(file_type) {
-                let mut buf = vec![0u8; header.len()];
-                if let Ok(num_bytes) = self.read_archive(file_type, 0, &mut buf) {
-                    if num_bytes != header.len() || buf != header {
-                        try!(self.write_archive(file_type, 0, &header));
-                    }
+            let expected = match overrides.iter().find(|&&(ref ft, _)| *ft == file_type) {
+                Some(&(_, ref header))  => Some(header.clone()),
+                None                    => FileHeader::new(file_type),
+            };
+
+            if let Some(expected) = expected {
+                let mut buf = [0u8; header::HEADER_SIZE];
+                let num_bytes = try!(self.read_archive(file_type, 0, &mut buf));
+
+                if num_bytes == 0 {
+                    try!(self.write_archive(file_type, 0, &expected.to_bytes()));
+                    continue;
+                }
+
+                let found = try!(FileHeader::from_bytes(&buf[..num_bytes]));
+
+                if found != expected {
+                    try!(self.migrate(file_type, &found, &expected));
+                    try!(self.write_archive(file_type, 0, &expected.to_bytes()));
                 }
             }
         }
@@ -41,7 +88,40 @@ pub trait Storage {
         Ok(())
     }
 
-    fn get_state(&mut self) -> Result<StorageState> {
+    // Upgrades a file whose header differs from the expected one, or fails
+    // if it can't. Trees written before the header matched hypercore claim
+    // 72 byte entries, though the entries were always 40 bytes, so only
+    // the header needs rewriting. Version 0 bitfields get their index
+    // recomputed.
+    fn migrate(&mut self, file_type: FileType, header: &FileHeader, expected: &FileHeader) -> Result<()> {
+        if let FileType::Tree = file_type {
+            if header.version == 0 && header.entry_size == 72 && header.algorithm == expected.algorithm {
+                return Ok(());
+            }
+        }
+
+        try!(header.validate(expected));
+
+        if header.version > expected.version {
+            return Err(Error::new(ErrorKind::Other, "Header version is newer than supported."));
+        }
+
+        if let FileType::Bitfield = file_type {
+            if header.version == 0 {
+                return reindex_bitfield(self);
+            }
+        }
+
+        Err(Error::new(ErrorKind::Other, "No migration available for header version."))
+    }
+
+    // A separate reader over the bitfield for a paged `Bitfield`, if this
+    // storage can provide one.
+    fn page_source(&self) -> Result<Option<Box<PageSource>>> {
+        Ok(None)
+    }
+
+    fn get_state(&self) -> Result<StorageState> {
         let mut bitfield = Vec::with_capacity(3328);
         let mut buf = [0u8; 3328];
         let mut offset = 32;
@@ -63,7 +143,14 @@ pub trait Storage {
         })
     }
 
-    fn get_offset(&mut self, index: u64) -> Result<Option<(u64, u64)>> {
+    // The offsets file holds the end offset of every stored block, so a
+    // block's range is the pair of entries ending at it. Anything not yet
+    // indexed falls back to summing the full roots to its left.
+    fn get_offset(&self, index: u64) -> Result<Option<(u64, u64)>> {
+        if let Some(range) = try!(self.get_indexed_offset(index)) {
+            return Ok(Some(range));
+        }
+
         let block = index;
         let roots = flat::full_roots(block);
         let mut offset = 0;
@@ -81,7 +168,59 @@ pub trait Storage {
         Ok(None)
     }
     
-    fn get_node(&mut self, index: u64) -> Result<Option<Node>> {
+    fn get_indexed_offset(&self, index: u64) -> Result<Option<(u64, u64)>> {
+        let block = index / 2;
+
+        if block == 0 {
+            let mut buf = [0u8; 8];
+            if try!(self.read_archive(FileType::Offsets, 0, &mut buf)) != 8 {
+                return Ok(None);
+            }
+            return match decode_u64(&buf) {
+                0   => Ok(None),
+                end => Ok(Some((0, end))),
+            };
+        }
+
+        let mut buf = [0u8; 16];
+        if try!(self.read_archive(FileType::Offsets, 8 * (block - 1), &mut buf)) != 16 {
+            return Ok(None);
+        }
+
+        let start = decode_u64(&buf[..8]);
+        let end = decode_u64(&buf[8..]);
+        if start == 0 || end <= start {
+            return Ok(None);
+        }
+
+        Ok(Some((start, end - start)))
+    }
+
+    fn put_offset(&mut self, index: u64, offset: u64, size: u64) -> Result<()> {
+        self.write_archive(FileType::Offsets, 8 * (index / 2), &encode_u64(offset + size))
+    }
+
+    // Fills in the offsets file for feeds written before it existed.
+    fn index_offsets(&mut self, blocks: u64) -> Result<()> {
+        if blocks == 0 || try!(self.get_indexed_offset(2 * (blocks - 1))).is_some() {
+            return Ok(());
+        }
+
+        let mut offset = 0;
+        for block in 0..blocks {
+            match try!(self.get_node(2 * block)) {
+                Some(node)  => {
+                    try!(self.put_offset(2 * block, offset, node.length));
+                    offset += node.length;
+                },
+                None        => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_node(&self, index: u64) -> Result<Option<Node>> {
         let mut buf = [0u8; 40];
 
         try!(self.read_archive(FileType::Tree, 32 + 40 * index, &mut buf));
@@ -113,7 +252,7 @@ pub trait Storage {
         self.write_archive(FileType::Tree, 32 + 40 * index, &buf)
     }
 
-    fn get_roots(&mut self, index: u64) -> Result<Vec<Node>> {
+    fn get_roots(&self, index: u64) -> Result<Vec<Node>> {
         let roots = flat::full_roots(2 * index);
         let mut result: Vec<Node> = Vec::with_capacity(roots.len());
         
@@ -126,7 +265,7 @@ pub trait Storage {
         Ok(result)
     } 
 
-    fn get_data(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
+    fn get_data(&self, index: u64) -> Result<Option<Vec<u8>>> {
         if let Some((offset, size)) = try!(self.get_offset(index)) {
             let mut buf: Vec<u8> = vec![0u8; size as usize];
             
@@ -140,31 +279,30 @@ pub trait Storage {
 
     fn put_data(&mut self, index: u64, data: Vec<u8>) -> Result<()> {
         if let Some((offset, size)) = try!(self.get_offset(index)) {
-            println!("Writing data");
-            
             if data.len() != size as usize {
                 return Err(Error::new(ErrorKind::Other, "Unexpected data size."));
             }
             
-            return self.write_archive(FileType::Data, offset, &data);
+            try!(self.write_archive(FileType::Data, offset, &data));
+            return self.put_offset(index, offset, size);
         }
 
         Ok(())
     }
 
-    fn get_signature(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
-        let mut hash: Vec<u8> = Vec::with_capacity(64);
+    fn get_signature(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        let mut hash: Vec<u8> = vec![0u8; 64];
     
-        try!(self.read_archive(FileType::Signatures, 32 + 64 * index, &mut hash));
+        let num_bytes = try!(self.read_archive(FileType::Signatures, 32 + 64 * index, &mut hash));
     
-        if hash_is_blank(&hash) {
+        if num_bytes != hash.len() || hash_is_blank(&hash) {
             return Ok(None);
         }
 
         Ok(Some(hash))
     }
 
-    fn next_signature(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
+    fn next_signature(&self, index: u64) -> Result<Option<Vec<u8>>> {
         match try!(self.get_signature(index)) {
             Some(hash)  => Ok(Some(hash)),
             None        => self.get_signature(index + 1)
@@ -179,7 +317,7 @@ pub trait Storage {
         self.write_archive(FileType::Bitfield, 32 + offset, &data)
     }
 
-    fn get_key(&mut self) -> Result<Option<[u8; 32]>> {
+    fn get_key(&self) -> Result<Option<[u8; 32]>> {
         let mut buf = [0u8; 32];
 
         let key_len = try!(self.read_archive(FileType::Key, 0, &mut buf));
@@ -195,7 +333,7 @@ pub trait Storage {
         self.write_archive(FileType::Key, 0, &data)
     }
 
-    fn get_secret(&mut self) -> Result<Option<[u8; 64]>> {
+    fn get_secret(&self) -> Result<Option<[u8; 64]>> {
         let mut buf = [0u8; 64];
 
         let secret_len = try!(self.read_archive(FileType::Secret, 0, &mut buf));
@@ -212,47 +350,35 @@ pub trait Storage {
     }
 }
 
-fn create_header(file_type: FileType) -> Option<[u8; 32]> {
-    let mut result = [0; 32];
-    let size: u16;
-    let hash: Vec<u8>;
-    let magic: u8;
-
-    match file_type {
-        FileType::Tree         => {
-            size = 72u16;
-            hash = b"BLAKE2b".to_vec();
-            magic = 2u8;
-        },
-        FileType::Signatures   => {
-            size = 64u16;
-            hash = b"Ed25519".to_vec();
-            magic = 1u8;
-        },
-        FileType::Bitfield     => {
-            size = 3328u16;
-            hash = b"".to_vec();
-            magic = 0u8;
-        },
-        _                       => {
-            return None;
-        },
+// Goes through the storage's page source when it has one, so only about
+// `REINDEX_BUDGET` bytes of the bitfield are held at a time.
+fn reindex_bitfield<S: Storage + ?Sized>(storage: &mut S) -> Result<()> {
+    if let Some(source) = try!(storage.page_source()) {
+        return Bitfield::reindex(source, REINDEX_BUDGET, &mut |offset, data| storage.put_bitfield(offset as u64, data));
     }
 
-    result[0] = 5u8;
-    result[1] = 2u8;
-    result[2] = 87u8;
-    result[3] = magic;
-
-    result[4] = 0u8;
-
-    result[5] = (size >> 8) as u8;
-    result[6] = size as u8;
+    let mut bitfield = try!(Bitfield::from_vec(try!(storage.get_state()).bitfield));
+    while let Some((offset, data)) = bitfield.last_updated() {
+        try!(storage.put_bitfield(offset as u64, data));
+    }
+    Ok(())
+}
 
-    result[7] = hash.len() as u8;
-    result[8..(8 + hash.len())].copy_from_slice(&hash);
+fn encode_u64(value: u64) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    for i in 0..8 {
+        buf[7 - i] = (value >> (8 * i)) as u8;
+    }
+    buf
+}
 
-    Some(result)
+fn decode_u64(buf: &[u8]) -> u64 {
+    let mut value: u64 = 0;
+    for i in 0..8 {
+        value <<= 8;
+        value += buf[i] as u64;
+    }
+    value
 }
 
 fn hash_is_blank(hash: &[u8]) -> bool {
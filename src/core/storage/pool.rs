@@ -0,0 +1,56 @@
+use std::io::Result;
+use std::sync::{Arc, RwLock};
+
+use futures_cpupool::CpuPool;
+
+use core::storage::{Storage, AsyncStorage, ArchiveFuture, FileType};
+
+// Runs a blocking `Storage` on a thread pool. Reads share the lock, writes
+// take it exclusively, and the protocol loop only ever sees futures.
+pub struct PooledStorage<T: Storage> {
+    storage:        Arc<RwLock<T>>,
+    pool:           CpuPool,
+}
+
+impl<T: Storage + Send + Sync + 'static> PooledStorage<T> {
+    pub fn new(storage: T) -> PooledStorage<T> {
+        PooledStorage::with_pool(storage, CpuPool::new_num_cpus())
+    }
+
+    pub fn with_pool(storage: T, pool: CpuPool) -> PooledStorage<T> {
+        PooledStorage {
+            storage:        Arc::new(RwLock::new(storage)),
+            pool:           pool,
+        }
+    }
+}
+
+impl<T: Storage> Clone for PooledStorage<T> {
+    fn clone(&self) -> PooledStorage<T> {
+        PooledStorage {
+            storage:        Arc::clone(&self.storage),
+            pool:           self.pool.clone(),
+        }
+    }
+}
+
+impl<T: Storage + Send + Sync + 'static> AsyncStorage for PooledStorage<T> {
+    fn read_archive(&self, file_type: FileType, offset: u64, len: usize) -> ArchiveFuture<Vec<u8>> {
+        let storage = Arc::clone(&self.storage);
+
+        Box::new(self.pool.spawn_fn(move || -> Result<Vec<u8>> {
+            let mut buf = vec![0u8; len];
+            let num_bytes = try!(storage.read().unwrap().read_archive(file_type, offset, &mut buf));
+            buf.truncate(num_bytes);
+            Ok(buf)
+        }))
+    }
+
+    fn write_archive(&self, file_type: FileType, offset: u64, data: Vec<u8>) -> ArchiveFuture<()> {
+        let storage = Arc::clone(&self.storage);
+
+        Box::new(self.pool.spawn_fn(move || -> Result<()> {
+            storage.write().unwrap().write_archive(file_type, offset, &data)
+        }))
+    }
+}
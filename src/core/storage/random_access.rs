@@ -0,0 +1,46 @@
+use std::cmp;
+use std::io::Result;
+use std::fs::File;
+
+use core::storage::file::{read_at, write_all_at};
+
+// A single byte-addressable file. `Storage` spreads a feed over several of
+// these, one per `FileType`.
+pub trait RandomAccess {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+}
+
+impl RandomAccess for File {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        read_at(self, offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        write_all_at(self, offset, buf)
+    }
+}
+
+impl RandomAccess for Vec<u8> {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.len() as u64 {
+            return Ok(0);
+        }
+
+        let start = offset as usize;
+        let end = cmp::min(self.len(), start + buf.len());
+        buf[..(end - start)].copy_from_slice(&self[start..end]);
+        Ok(end - start)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let start = offset as usize;
+        let end = start + buf.len();
+
+        if end > self.len() {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -7,8 +7,10 @@ extern crate ed25519_dalek;
 extern crate blake2;
 extern crate indexmap;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate quick_protobuf;
+extern crate memmap;
 
 pub mod core;
 pub mod common;
-// pub mod protocol;
\ No newline at end of file
+pub mod protocol;
\ No newline at end of file
@@ -1 +1,4 @@
-mod schema;
\ No newline at end of file
+pub mod schema;
+mod request;
+
+pub use self::request::{request, answer, encode, decode};
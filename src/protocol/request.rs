@@ -0,0 +1,62 @@
+use std::io;
+use std::io::Result;
+use std::borrow::Cow;
+
+use quick_protobuf::{MessageRead, MessageWrite, BytesReader, Writer};
+
+use common::merkle::TreeHash;
+use core::hypercore::Hypercore;
+use core::storage::Storage;
+use protocol::schema::{Request, Data};
+use protocol::schema::mod_Data::Node;
+
+// Asks for block `index`, telling the remote which tree nodes `feed`
+// already has so the proof sent back leaves them out.
+pub fn request<T: Storage, H: TreeHash>(feed: &Hypercore<T, H>, index: u64) -> Result<Request> {
+    Ok(Request {
+        index:  index,
+        bytes:  None,
+        hash:   None,
+        nodes:  Some(try!(feed.digest(index))),
+    })
+}
+
+// The `Data` answering `request`. The block itself is left out when only
+// its hash was asked for.
+pub fn answer<T: Storage, H: TreeHash>(feed: &Hypercore<T, H>, request: &Request) -> Result<Data<'static>> {
+    if request.bytes.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Other, "Byte offset requests aren't supported."));
+    }
+
+    let value = match request.hash {
+        Some(true)  => None,
+        _           => try!(feed.get(request.index)),
+    };
+    let proof = try!(feed.proof(request.index, request.nodes.unwrap_or(0)));
+
+    Ok(Data {
+        index:      proof.index,
+        value:      value.map(Cow::Owned),
+        nodes:      proof.nodes.into_iter().map(|node| Node {
+            index:  node.index,
+            hash:   Cow::Owned(node.hash.to_vec()),
+            size:   node.length,
+        }).collect(),
+        signature:  proof.signature.map(Cow::Owned),
+    })
+}
+
+pub fn encode<M: MessageWrite>(message: &M) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(message.get_size());
+    match message.write_message(&mut Writer::new(&mut buf)) {
+        Ok(())  => Ok(buf),
+        Err(_)  => Err(io::Error::new(io::ErrorKind::Other, "Unable to encode message.")),
+    }
+}
+
+pub fn decode<'a, M: MessageRead<'a>>(buf: &'a [u8]) -> Result<M> {
+    match M::from_reader(&mut BytesReader::from_bytes(buf), buf) {
+        Ok(message) => Ok(message),
+        Err(_)      => Err(io::Error::new(io::ErrorKind::Other, "Invalid message.")),
+    }
+}
@@ -0,0 +1,622 @@
+extern crate dat;
+extern crate rand;
+
+mod common;
+
+use std::cmp;
+use std::io::{Result, Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::Rng;
+
+use dat::common::rle;
+use dat::common::sparse::SparseBitfield;
+use dat::common::pager::{Pager, PageSource};
+use dat::core::bitfield::{Bitfield, ProofOpts};
+use dat::core::remote::RemoteBitfield;
+
+use common::seeded_rng;
+
+#[test]
+fn test_rle_runs() {
+    assert_eq!(rle::encode(&[255u8; 16]), vec![16 << 2 | 3]);
+    assert_eq!(rle::encode(&[0u8; 1000]), vec![0xa1, 0x1f]);
+    assert_eq!(rle::encode(&[1, 2, 3]), vec![3 << 1, 1, 2, 3]);
+    assert_eq!(rle::encode(&[1, 0, 0, 0, 0, 255]), vec![1 << 1, 1, 4 << 2 | 1, 1 << 1, 255]);
+}
+
+#[test]
+fn test_rle_round_trip() {
+    let mut rng = seeded_rng();
+
+    for _ in 0..100 {
+        let mut bitfield: Vec<u8> = Vec::new();
+        for _ in 0..rng.gen_range(0, 20) {
+            let byte = match rng.gen_range(0, 3) {
+                0   => 0,
+                1   => 255,
+                _   => rng.gen(),
+            };
+            let run = rng.gen_range(1, 50);
+            bitfield.extend(vec![byte; run]);
+        }
+
+        let encoded = rle::encode(&bitfield);
+        assert!(encoded.len() <= bitfield.len() + 10);
+        assert_eq!(rle::decode(&encoded, bitfield.len()).unwrap(), bitfield);
+    }
+}
+
+#[test]
+fn test_rle_rejects_malformed() {
+    assert!(rle::decode(&[4 << 1, 1, 2], 100).is_err());
+    assert!(rle::decode(&[0x80], 100).is_err());
+}
+
+#[test]
+fn test_rle_rejects_oversized() {
+    // Nine bytes claiming a run of about 2^60 bytes.
+    let huge = [0xf7, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+    assert!(rle::decode(&huge, 1 << 20).is_err());
+    assert!(rle::decode(&[16 << 2 | 3], 15).is_err());
+    assert!(rle::decode(&[3 << 1, 1, 2, 3], 2).is_err());
+    assert_eq!(rle::decode(&[16 << 2 | 3], 16).unwrap(), vec![255u8; 16]);
+}
+
+#[test]
+fn test_rle_matches_javascript() {
+    // What a JS peer sends for blocks 0 to 35 and 40: four 0xff bytes as a
+    // run, then 0xf0 0x80 raw, high bit first.
+    let message = [4 << 2 | 3, 2 << 1, 0xf0, 0x80];
+
+    let mut bitfield = Bitfield::new();
+    bitfield.set_range(0..36, true).unwrap();
+    bitfield.set(40, true).unwrap();
+    assert_eq!(bitfield.to_rle(0..41).unwrap(), message.to_vec());
+
+    let decoded = Bitfield::from_rle(0, &message, 1000).unwrap();
+    let mut remote = RemoteBitfield::new();
+    remote.have(0, 0, Some(&message)).unwrap();
+    for i in 0..48 {
+        assert_eq!(decoded.get(i).unwrap(), i < 36 || i == 40);
+        assert_eq!(remote.get(i).unwrap(), i < 36 || i == 40);
+    }
+
+    assert_eq!(Bitfield::from_rle(0, &[1 << 1, 0x40], 8).unwrap().present(0..8).collect::<Result<Vec<u64>>>().unwrap(), vec![1]);
+}
+
+#[test]
+fn test_bitfield_rle() {
+    let mut bitfield = Bitfield::new();
+    for i in 0..1000 {
+        bitfield.set(i, true).unwrap();
+    }
+    bitfield.set(2000, true).unwrap();
+
+    let encoded = bitfield.to_rle(0..2008).unwrap();
+    assert!(encoded.len() < 10);
+
+    let decoded = Bitfield::from_rle(0, &encoded, 2008).unwrap();
+    for i in 0..2008 {
+        assert_eq!(decoded.get(i).unwrap(), i < 1000 || i == 2000);
+    }
+
+    let partial = Bitfield::from_rle(3, &bitfield.to_rle(3..13).unwrap(), 16).unwrap();
+    for i in 0..16 {
+        assert_eq!(partial.get(i).unwrap(), i >= 3 && i < 13);
+    }
+    // Claimed blocks aren't verified ones, so the tree stays empty.
+    assert_eq!(decoded.blocks().unwrap(), 0);
+    assert_eq!(decoded.verfied_by(0).unwrap(), None);
+
+    let mut full = Bitfield::new();
+    full.set_range(0..(1 << 18), true).unwrap();
+    let claimed = Bitfield::from_rle(0, &full.to_rle(0..(1 << 18)).unwrap(), 1 << 20).unwrap();
+    assert_eq!(claimed.find_first(false, 0..(1 << 18)).unwrap(), None);
+    assert_eq!(claimed.total(0..(1 << 18)).unwrap(), 1 << 18);
+
+    let capped = Bitfield::from_rle(0, &full.to_rle(0..8).unwrap(), 5).unwrap();
+    assert_eq!(capped.present(0..16).collect::<Result<Vec<u64>>>().unwrap(), (0..5).collect::<Vec<u64>>());
+}
+
+#[test]
+fn test_remote_have_and_unhave() {
+    let mut remote = RemoteBitfield::new();
+    remote.have(5, 20, None).unwrap();
+    remote.unhave(10, 3).unwrap();
+
+    for i in 0..32 {
+        assert_eq!(remote.get(i).unwrap(), i >= 5 && i < 25 && !(i >= 10 && i < 13));
+    }
+
+    remote.set_range(0..64, false).unwrap();
+    assert!((0..64).all(|i| !remote.get(i).unwrap()));
+}
+
+#[test]
+fn test_remote_rejects_bad_ranges() {
+    let mut remote = RemoteBitfield::with_max_length(1000);
+    assert!(remote.have(u64::max_value() - 5, 10, None).is_err());
+    assert!(remote.unhave(u64::max_value() - 5, 10).is_err());
+    assert!(remote.have(1000, 10, None).is_err());
+
+    remote.have(990, u64::max_value() - 990, None).unwrap();
+    assert!(remote.get(999).unwrap());
+    assert!(!remote.get(1000).unwrap());
+    assert_eq!(remote.data().len(), 1024 * 8);
+
+    remote.unhave(995, u64::max_value() - 995).unwrap();
+    assert!(remote.get(994).unwrap());
+    assert!(!remote.get(995).unwrap());
+}
+
+#[test]
+fn test_remote_have_rle() {
+    let mut local = Bitfield::new();
+    for i in 0..100 {
+        local.set(i, true).unwrap();
+    }
+
+    let mut remote = RemoteBitfield::new();
+    remote.have(3, 50, None).unwrap();
+    remote.have(0, 0, Some(&local.to_rle(0..100).unwrap())).unwrap();
+
+    for i in 0..112 {
+        assert_eq!(remote.get(i).unwrap(), i < 100);
+    }
+}
+
+#[test]
+fn test_remote_first_wanted() {
+    let mut local = Bitfield::new();
+    for i in 0..40 {
+        local.set(i, true).unwrap();
+    }
+
+    let mut remote = RemoteBitfield::new();
+    remote.have(0, 30, None).unwrap();
+    assert_eq!(remote.first_wanted(&local, 0..1000).unwrap(), None);
+
+    remote.have(900, 10, None).unwrap();
+    remote.have(35, 7, None).unwrap();
+    assert_eq!(remote.first_wanted(&local, 0..1000).unwrap(), Some(40));
+    assert_eq!(remote.first_wanted(&local, 42..1000).unwrap(), Some(900));
+    assert_eq!(remote.first_wanted(&local, 42..900).unwrap(), None);
+}
+
+#[test]
+fn test_present_and_missing() {
+    let mut rng = seeded_rng();
+    let mut bitfield = Bitfield::new();
+    let mut expected = vec![false; 30000];
+
+    for _ in 0..20 {
+        let start = rng.gen_range(0, 29000);
+        let value = rng.gen();
+        for i in start..(start + rng.gen_range(1, 1000)) {
+            bitfield.set(i as u64, value).unwrap();
+            expected[i] = value;
+        }
+    }
+
+    let present: Vec<u64> = (0..30000).filter(|&i| expected[i as usize]).collect();
+    let missing: Vec<u64> = (0..30000).filter(|&i| !expected[i as usize]).collect();
+    assert_eq!(bitfield.present(0..30000).collect::<Result<Vec<u64>>>().unwrap(), present);
+    assert_eq!(bitfield.missing(0..30000).collect::<Result<Vec<u64>>>().unwrap(), missing);
+
+    let reloaded = Bitfield::from_vec(bitfield.to_vec().unwrap()).unwrap();
+    assert_eq!(reloaded.present(0..30000).collect::<Result<Vec<u64>>>().unwrap(), present);
+    assert_eq!(reloaded.missing(100..200).collect::<Result<Vec<u64>>>().unwrap(),
+               missing.iter().cloned().filter(|&i| i >= 100 && i < 200).collect::<Vec<u64>>());
+}
+
+#[test]
+fn test_missing_skips_full_regions() {
+    let mut bitfield = Bitfield::new();
+    for i in 0..100000 {
+        bitfield.set(i, true).unwrap();
+    }
+    bitfield.set(54321, false).unwrap();
+
+    assert_eq!(bitfield.missing(0..100000).collect::<Result<Vec<u64>>>().unwrap(), vec![54321]);
+    assert_eq!(bitfield.missing(0..100002).collect::<Result<Vec<u64>>>().unwrap(), vec![54321, 100000, 100001]);
+    assert_eq!(bitfield.present(99998..1000000).collect::<Result<Vec<u64>>>().unwrap(), vec![99998, 99999]);
+}
+
+#[test]
+fn test_sparse_present_and_missing() {
+    let mut bitfield = SparseBitfield::new();
+    for &i in &[3, 4, 5, 100, 50000, 1000000] {
+        bitfield.set(i, true).unwrap();
+    }
+
+    assert_eq!(bitfield.present(0..2000000).collect::<Result<Vec<u64>>>().unwrap(), vec![3, 4, 5, 100, 50000, 1000000]);
+    assert_eq!(bitfield.present(5..50000).collect::<Result<Vec<u64>>>().unwrap(), vec![5, 100]);
+    assert_eq!(bitfield.missing(0..8).collect::<Result<Vec<u64>>>().unwrap(), vec![0, 1, 2, 6, 7]);
+}
+
+#[test]
+fn test_set_range_matches_set() {
+    let mut rng = seeded_rng();
+    let mut ranged = Bitfield::new();
+    let mut single = Bitfield::new();
+
+    for _ in 0..30 {
+        let start = rng.gen_range(0, 20000);
+        let end = start + rng.gen_range(1, 3000);
+        let value = rng.gen_range(0, 3) > 0;
+
+        ranged.set_range(start..end, value).unwrap();
+        for i in start..end {
+            single.set(i, value).unwrap();
+        }
+
+        assert_eq!(ranged.to_vec().unwrap(), single.to_vec().unwrap());
+    }
+
+    assert_eq!(ranged.blocks().unwrap(), single.blocks().unwrap());
+}
+
+#[test]
+fn test_set_range() {
+    let mut bitfield = Bitfield::new();
+    assert!(bitfield.set_range(0..100, true).unwrap());
+    assert!(!bitfield.set_range(10..20, true).unwrap());
+    assert_eq!(bitfield.blocks().unwrap(), 100);
+
+    let mut pages = 0;
+    while let Some(_) = bitfield.last_updated() {
+        pages += 1;
+    }
+    assert_eq!(pages, 1);
+
+    assert!(bitfield.set_range(50..60, false).unwrap());
+    assert_eq!(bitfield.blocks().unwrap(), 50);
+    assert_eq!(bitfield.missing(0..100).collect::<Result<Vec<u64>>>().unwrap(), (50..60).collect::<Vec<u64>>());
+}
+
+struct VecSource {
+    buf:        Vec<u8>,
+}
+
+impl PageSource for VecSource {
+    fn pages(&self) -> Result<usize> {
+        Ok((self.buf.len() + 3327) / 3328)
+    }
+
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<usize> {
+        let start = cmp::min(self.buf.len(), index * 3328);
+        let end = cmp::min(self.buf.len(), start + buf.len());
+        buf[..(end - start)].copy_from_slice(&self.buf[start..end]);
+        Ok(end - start)
+    }
+}
+
+#[test]
+fn test_paged_bitfield() {
+    let mut full = Bitfield::new();
+    full.set_range(0..40000, true).unwrap();
+    full.set_range(1000..1010, false).unwrap();
+    full.set(100000, true).unwrap();
+
+    let buf = full.to_vec().unwrap();
+    let source = Box::new(VecSource { buf: buf.clone() });
+    let mut paged = Bitfield::paged(source, 2 * 3328).unwrap();
+
+    // Past the end it has the index root it grew on open.
+    assert_eq!(paged.blocks().unwrap(), full.blocks().unwrap());
+    assert_eq!(&paged.to_vec().unwrap()[..buf.len()], &buf[..]);
+    assert_eq!(paged.missing(0..40000).collect::<Result<Vec<u64>>>().unwrap(), (1000..1010).collect::<Vec<u64>>());
+    assert!(paged.get(100000).unwrap());
+    assert!(!paged.get(100001).unwrap());
+
+    paged.set(200000, true).unwrap();
+    paged.set(5, false).unwrap();
+    assert!(paged.get(200000).unwrap());
+    assert!(!paged.get(5).unwrap());
+    assert!(paged.get(6).unwrap());
+}
+
+struct CountingSource {
+    inner:      VecSource,
+    loads:      Arc<AtomicUsize>,
+}
+
+impl PageSource for CountingSource {
+    fn pages(&self) -> Result<usize> {
+        self.inner.pages()
+    }
+
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<usize> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_page(index, buf)
+    }
+}
+
+#[test]
+fn test_find_skips_uniform_pages() {
+    let page_bits = 1024 * 8;
+    let mut full = Bitfield::new();
+    full.set_range(0..(200 * page_bits), true).unwrap();
+    full.set(150 * page_bits + 7, false).unwrap();
+    full.set(400 * page_bits + 3, true).unwrap();
+
+    let loads = Arc::new(AtomicUsize::new(0));
+    let source = CountingSource {
+        inner:  VecSource { buf: full.to_vec().unwrap() },
+        loads:  Arc::clone(&loads),
+    };
+    let paged = Bitfield::paged(Box::new(source), 4 * 3328).unwrap();
+
+    // Walking the runs a page at a time would load every one of them.
+    loads.store(0, Ordering::SeqCst);
+    assert_eq!(paged.find_first(false, 0..u64::max_value()).unwrap(), Some(150 * page_bits + 7));
+    assert!(loads.load(Ordering::SeqCst) < 64);
+
+    loads.store(0, Ordering::SeqCst);
+    assert_eq!(paged.find_first(true, (200 * page_bits)..u64::max_value()).unwrap(), Some(400 * page_bits + 3));
+    assert!(loads.load(Ordering::SeqCst) < 64);
+
+    loads.store(0, Ordering::SeqCst);
+    assert_eq!(paged.find_last(false, 0..(150 * page_bits)).unwrap(), None);
+    assert_eq!(paged.find_last(true, 0..(400 * page_bits)).unwrap(), Some(200 * page_bits - 1));
+    assert!(loads.load(Ordering::SeqCst) < 128);
+}
+
+#[test]
+fn test_sparse_len() {
+    let mut sparse = SparseBitfield::new();
+    sparse.set(5, true).unwrap();
+    assert_eq!(sparse.len(), 1024 * 8);
+    sparse.set(3 * 1024 * 8, true).unwrap();
+    assert_eq!(sparse.len(), 4 * 1024 * 8);
+}
+
+#[test]
+fn test_pager_budget() {
+    let mut full = Pager::new();
+    for page in 0..10 {
+        full.set(page, 0, 1).unwrap();
+    }
+    let mut buf = vec![0u8; 10 * 3328];
+    for (&index, page) in full.iter() {
+        buf[(index * 3328)..((index + 1) * 3328)].copy_from_slice(page);
+    }
+
+    let mut pager = Pager::with_source(Box::new(VecSource { buf: buf }), 3 * 3328).unwrap();
+    assert_eq!(pager.len(), 10);
+
+    for page in 0..10 {
+        assert!(pager.load(page).unwrap());
+        assert_eq!(pager.get(page).unwrap()[0], 1);
+        assert!(pager.iter().count() <= 3);
+    }
+    assert!(!pager.load(10).unwrap());
+
+    for page in 0..5 {
+        pager.set(page, 1, 2).unwrap();
+    }
+    assert_eq!(pager.iter().count(), 5);
+
+    let mut flushed = Vec::new();
+    while let Some(page) = pager.last_updated() {
+        flushed.push(page);
+    }
+    flushed.sort();
+    assert_eq!(flushed, vec![0, 1, 2, 3, 4]);
+
+    pager.load(9).unwrap();
+    assert!(pager.iter().count() <= 3);
+
+    // Reading a page keeps it over ones loaded after it.
+    for page in 6..9 {
+        pager.load(page).unwrap();
+    }
+    pager.get(6).unwrap();
+    pager.load(9).unwrap();
+    assert!(pager.get(6).is_some());
+    assert!(pager.get(7).is_none());
+}
+
+#[test]
+fn test_find_first_and_last() {
+    let mut rng = seeded_rng();
+    let mut bitfield = Bitfield::new();
+    let mut sparse = SparseBitfield::new();
+    let mut expected = vec![false; 40000];
+
+    for _ in 0..10 {
+        let start = rng.gen_range(0, 39000);
+        let end = start + rng.gen_range(1, 1000);
+        bitfield.set_range(start..end, true).unwrap();
+        for i in start..end {
+            sparse.set(i, true).unwrap();
+            expected[i as usize] = true;
+        }
+    }
+
+    for _ in 0..200 {
+        let start = rng.gen_range(0, 40000);
+        let end = rng.gen_range(start, 40001);
+        for &value in &[true, false] {
+            let first = (start..end).find(|&i| expected[i as usize] == value);
+            let last = (start..end).rev().find(|&i| expected[i as usize] == value);
+            assert_eq!(bitfield.find_first(value, start..end).unwrap(), first);
+            assert_eq!(bitfield.find_last(value, start..end).unwrap(), last);
+            assert_eq!(sparse.find_first(value, start..end).unwrap(), first);
+            assert_eq!(sparse.find_last(value, start..end).unwrap(), last);
+        }
+    }
+}
+
+#[test]
+fn test_find_in_huge_range() {
+    let mut bitfield = Bitfield::new();
+    bitfield.set_range(0..5000, true).unwrap();
+    bitfield.set(3000000, true).unwrap();
+
+    assert_eq!(bitfield.find_first(false, 0..u64::max_value()).unwrap(), Some(5000));
+    assert_eq!(bitfield.find_first(true, 5000..u64::max_value()).unwrap(), Some(3000000));
+    assert_eq!(bitfield.find_last(true, 0..u64::max_value()).unwrap(), Some(3000000));
+    assert_eq!(bitfield.find_last(true, 0..3000000).unwrap(), Some(4999));
+    assert_eq!(bitfield.find_last(false, 0..5000).unwrap(), None);
+}
+
+#[test]
+fn test_set_operations() {
+    let mut rng = seeded_rng();
+
+    for _ in 0..20 {
+        let mut a = SparseBitfield::new();
+        let mut b = SparseBitfield::new();
+        let mut expected_a = vec![false; 20000];
+        let mut expected_b = vec![false; 20000];
+        for _ in 0..10 {
+            let start = rng.gen_range(0, 19000);
+            for i in start..(start + rng.gen_range(1, 1000)) {
+                if rng.gen_range(0, 4) > 0 {
+                    a.set(i as u64, true).unwrap();
+                    expected_a[i] = true;
+                }
+                if rng.gen_range(0, 4) == 0 {
+                    b.set(i as u64, true).unwrap();
+                    expected_b[i] = true;
+                }
+            }
+        }
+
+        let start = rng.gen_range(0, 20000);
+        let end = rng.gen_range(start, 20001);
+        let count = (start..end).filter(|&i| expected_a[i as usize]).count() as u64;
+        assert_eq!(a.count_ones(start..end).unwrap(), count);
+
+        let op = rng.gen_range(0, 3);
+        match op {
+            0   => a.and(&b, start..end).unwrap(),
+            1   => a.or(&b, start..end).unwrap(),
+            _   => a.and_not(&b, start..end).unwrap(),
+        }
+
+        for i in 0..20000 {
+            let (x, y) = (expected_a[i], expected_b[i]);
+            let inside = i as u64 >= start && (i as u64) < end;
+            let value = match op {
+                _ if !inside    => x,
+                0               => x && y,
+                1               => x || y,
+                _               => x && !y,
+            };
+            assert_eq!(a.get(i as u64).unwrap(), value);
+        }
+    }
+}
+
+#[test]
+fn test_bitfield_set_operations() {
+    let mut local = Bitfield::new();
+    local.set_range(0..30000, true).unwrap();
+    local.set_range(100..200, false).unwrap();
+
+    let mut other = Bitfield::new();
+    other.set_range(150..20000, true).unwrap();
+
+    local.and_not(&other, 0..u64::max_value()).unwrap();
+    assert_eq!(local.total(0..40000).unwrap(), 100 + 10000);
+    assert_eq!(local.present(0..40000).collect::<Result<Vec<u64>>>().unwrap(),
+               (0..100).chain(20000..30000).collect::<Vec<u64>>());
+    assert_eq!(local.find_first(false, 0..40000).unwrap(), Some(100));
+
+    local.or(&other, 1000..2000).unwrap();
+    local.and(&other, 0..10).unwrap();
+    assert_eq!(local.missing(0..30000).collect::<Result<Vec<u64>>>().unwrap().len(), 30000 - (90 + 1000 + 10000));
+    assert_eq!(local.total(5..1005).unwrap(), 90 + 5);
+}
+
+#[test]
+fn test_plan_across_peers() {
+    let mut local = Bitfield::new();
+    local.set_range(0..100, true).unwrap();
+
+    let mut peers = vec![RemoteBitfield::new(), RemoteBitfield::new(), RemoteBitfield::new()];
+    peers[0].have(0, 200, None).unwrap();
+    peers[1].have(50, 300, None).unwrap();
+    peers[2].have(340, 20, None).unwrap();
+
+    // What any peer has that we lack.
+    let mut wanted = SparseBitfield::new();
+    for peer in &peers {
+        wanted.or(peer.data(), 0..1000).unwrap();
+    }
+    wanted.and_not(local.data(), 0..1000).unwrap();
+    assert_eq!(wanted.count_ones(0..1000).unwrap(), 260);
+    assert_eq!(wanted.find_first(true, 0..1000).unwrap(), Some(100));
+
+    // What exactly one peer has, to ask for the rarest blocks first.
+    let mut once = SparseBitfield::new();
+    let mut twice = SparseBitfield::new();
+    for peer in &peers {
+        let mut both = SparseBitfield::new();
+        both.or(peer.data(), 0..1000).unwrap();
+        both.and(&once, 0..1000).unwrap();
+        twice.or(&both, 0..1000).unwrap();
+        once.or(peer.data(), 0..1000).unwrap();
+    }
+    once.and_not(&twice, 0..1000).unwrap();
+    once.and_not(local.data(), 0..1000).unwrap();
+    assert_eq!(once.present(0..1000).collect::<Result<Vec<u64>>>().unwrap(),
+               (200..340).chain(350..360).collect::<Vec<u64>>());
+}
+
+#[test]
+fn test_proof_skips_known_nodes() {
+    let mut local = Bitfield::new();
+    local.set_range(0..8, true).unwrap();
+
+    let mut remote = Bitfield::new();
+    remote.set_range(0..5, true).unwrap();
+
+    assert_eq!(local.proof(10, ProofOpts::new()).unwrap(), Some((vec![8, 13, 3], 16)));
+
+    let mut opts = ProofOpts::new();
+    opts.set_digest(remote.digest(10).unwrap());
+    assert_eq!(local.proof(10, opts).unwrap(), Some((vec![13], 16)));
+
+    remote.set(5, true).unwrap();
+    let mut opts = ProofOpts::new();
+    opts.set_digest(remote.digest(10).unwrap());
+    opts.set_hash(true);
+    assert_eq!(local.proof(10, opts).unwrap(), Some((vec![10], 0)));
+
+    assert_eq!(local.proof(16, ProofOpts::new()).unwrap(), None);
+}
+
+struct FailingSource {
+    pages:      usize,
+}
+
+impl PageSource for FailingSource {
+    fn pages(&self) -> Result<usize> {
+        Ok(self.pages)
+    }
+
+    fn read_page(&self, _: usize, _: &mut [u8]) -> Result<usize> {
+        Err(Error::new(ErrorKind::Other, "Disk is gone."))
+    }
+}
+
+#[test]
+fn test_paged_errors_propagate() {
+    assert!(Bitfield::paged(Box::new(FailingSource { pages: 4 }), 3328).is_err());
+
+    let mut bitfield = Bitfield::paged(Box::new(FailingSource { pages: 1 }), 3328).unwrap();
+    assert!(bitfield.get(5).is_err());
+    assert!(bitfield.set(5, true).is_err());
+    assert!(bitfield.find_first(true, 0..100).is_err());
+    assert!(bitfield.present(0..100).next().unwrap().is_err());
+    assert!(bitfield.to_vec().is_err());
+
+    let mut pager = Pager::with_source(Box::new(FailingSource { pages: 4 }), 3328).unwrap();
+    assert!(pager.set(0, 0, 1).is_err());
+    assert!(pager.last_updated().is_none());
+}
@@ -0,0 +1,22 @@
+// Helpers shared by the integration tests and the benchmarks. Each test
+// crate only uses some of them.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::PathBuf;
+
+use rand::{SeedableRng, XorShiftRng};
+
+// An empty directory under the system temp dir, cleared of any earlier run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    let _ = remove_dir_all(&path);
+    create_dir_all(&path).unwrap();
+    path
+}
+
+// Seeded so a failure can be replayed.
+pub fn seeded_rng() -> XorShiftRng {
+    XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb])
+}
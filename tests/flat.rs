@@ -0,0 +1,113 @@
+extern crate dat;
+extern crate rand;
+
+mod common;
+
+use rand::Rng;
+use dat::common::flat;
+
+use common::seeded_rng;
+
+// A random node with room above it for a few parents.
+fn gen_index<R: Rng>(rng: &mut R) -> u64 {
+    let depth = rng.gen_range(0, 20);
+    flat::index(depth, rng.gen_range(0, 1 << 20))
+}
+
+#[test]
+fn test_iterator_seek() {
+    let mut rng = seeded_rng();
+    let mut iter = flat::TreeIterator::new(0);
+
+    for _ in 0..1000 {
+        let index = gen_index(&mut rng);
+        iter.seek(index);
+        assert_eq!(iter.index(), index);
+        assert_eq!(iter.offset(), flat::offset(index));
+        assert_eq!(iter.depth(), flat::depth(index));
+        assert_eq!(iter.is_left(), flat::is_left(index));
+        assert_eq!(iter.is_right(), flat::is_right(index));
+    }
+}
+
+#[test]
+fn test_iterator_moves() {
+    let mut rng = seeded_rng();
+
+    for _ in 0..1000 {
+        let index = gen_index(&mut rng);
+        let mut iter = flat::TreeIterator::new(index);
+
+        assert_eq!(iter.sibling(), flat::sibling(index));
+        assert_eq!(iter.sibling(), index);
+        assert_eq!(iter.parent(), flat::parent(index));
+        assert_eq!(iter.offset(), flat::offset(flat::parent(index)));
+
+        iter.seek(index);
+        assert_eq!(iter.left_child(), flat::left_child(index).unwrap_or(index));
+        iter.seek(index);
+        assert_eq!(iter.right_child(), flat::right_child(index).unwrap_or(index));
+        iter.seek(index);
+        assert_eq!(iter.left_span(), flat::left_span(index));
+        iter.seek(index);
+        assert_eq!(iter.right_span(), flat::right_span(index));
+        iter.seek(index);
+        assert_eq!(iter.next_node(), flat::index(flat::depth(index), flat::offset(index) + 1));
+        assert_eq!(iter.prev_node(), index);
+    }
+}
+
+#[test]
+fn test_iterator_walks() {
+    let mut rng = seeded_rng();
+
+    for _ in 0..200 {
+        let index = gen_index(&mut rng);
+        let mut iter = flat::TreeIterator::new(index);
+        let mut expected = index;
+
+        for _ in 0..30 {
+            match rng.gen_range(0, 4) {
+                0   => {
+                    expected = flat::parent(expected);
+                    assert_eq!(iter.parent(), expected);
+                },
+                1   => {
+                    expected = flat::sibling(expected);
+                    assert_eq!(iter.sibling(), expected);
+                },
+                2   => {
+                    expected = flat::left_child(expected).unwrap_or(expected);
+                    assert_eq!(iter.left_child(), expected);
+                },
+                _   => {
+                    expected = flat::right_child(expected).unwrap_or(expected);
+                    assert_eq!(iter.right_child(), expected);
+                },
+            }
+            assert_eq!(iter.offset(), flat::offset(expected));
+            assert_eq!(iter.depth(), flat::depth(expected));
+        }
+    }
+}
+
+#[test]
+fn test_iterator_full_roots() {
+    let mut rng = seeded_rng();
+
+    for _ in 0..1000 {
+        let index = 2 * rng.gen_range(0, 1 << 30);
+        let mut iter = flat::TreeIterator::new(0);
+        let mut roots = Vec::new();
+        while iter.full_root(index) {
+            roots.push(iter.index());
+            iter.next_tree();
+        }
+        assert_eq!(roots, flat::full_roots(index));
+    }
+
+    let mut iter = flat::TreeIterator::new(0);
+    assert!(!iter.full_root(0));
+    iter.seek(1);
+    assert!(!iter.full_root(8));
+}
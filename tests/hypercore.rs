@@ -1,10 +1,31 @@
 extern crate dat;
+extern crate rand;
+extern crate sha2;
+extern crate blake2;
 
-use std::fs::remove_dir_all;
+mod common;
+
+use std::io::Result;
+use std::fs::{create_dir_all, remove_dir_all, read, write};
 use std::path::Path;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use rand::{SeedableRng, XorShiftRng};
+use sha2::Sha256;
+use blake2::Blake2b;
+
+use dat::core::{Hypercore, HypercoreBuilder, SharedHypercore};
+use dat::core::storage::{Storage, FileStorage, CachedStorage, MemoryStorage, MmapStorage};
+use dat::core::keystore::{KeyStore, FileKeyStore, MemoryKeyStore};
+use dat::core::signer::{Signer, Verifier, Ed25519Signer, Ed25519Verifier};
+use dat::core::bitfield::Bitfield;
+use dat::common::merkle::{Node, TreeHash};
 
-use dat::core::Hypercore;
-use dat::core::storage::{FileStorage, CachedStorage, MemoryStorage};
+use common::temp_dir;
 
 const DIR_PATH: &str = "/home/vader/test";
 
@@ -59,3 +80,528 @@ fn test_cached_storage() {
     }
 }
 
+#[test]
+fn test_mmap_storage() {
+    let path = temp_dir("dat-test-mmap");
+    let data = vec![0u8; 1024 * 64];
+
+    {
+        let storage = MmapStorage::new(&path).unwrap();
+        let mut feed = Hypercore::new(storage).unwrap();
+
+        for i in 0..64  {
+            feed.append(data.clone()).unwrap();
+            assert_eq!(feed.get(i).unwrap().unwrap(), data.clone());
+        }
+    }
+
+    let storage = MmapStorage::new(&path).unwrap();
+    let feed = Hypercore::new(storage).unwrap();
+    for i in 0..64 {
+        assert_eq!(feed.get(i).unwrap().unwrap(), data.clone());
+    }
+}
+
+#[test]
+fn test_shared_hypercore() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let feed = SharedHypercore::new(MemoryStorage::new()).unwrap();
+    let data = vec![7u8; 1024];
+    assert_send_sync(&feed);
+
+    for _ in 0..16 {
+        feed.append(data.clone()).unwrap();
+    }
+
+    let readers: Vec<_> = (0..4).map(|_| {
+        let feed = feed.clone();
+        let data = data.clone();
+        thread::spawn(move || {
+            for i in 0..16 {
+                assert!(feed.has(i).unwrap());
+                assert_eq!(feed.get(i).unwrap().unwrap(), data);
+            }
+        })
+    }).collect();
+
+    let writer = {
+        let feed = feed.clone();
+        let data = data.clone();
+        thread::spawn(move || {
+            for _ in 0..16 {
+                feed.append(data.clone()).unwrap();
+            }
+        })
+    };
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    writer.join().unwrap();
+
+    for i in 0..32 {
+        assert_eq!(feed.get(i).unwrap().unwrap(), data);
+    }
+}
+
+#[test]
+fn test_file_keystore() {
+    let path = temp_dir("dat-test-keystore");
+    let keys = temp_dir("dat-test-keystore-keys");
+    let mut keystore = FileKeyStore::new(&keys).unwrap();
+
+    {
+        let mut feed = HypercoreBuilder::new().keystore(&mut keystore).build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"secret".to_vec()).unwrap();
+    }
+
+    let key = FileStorage::new(&path).unwrap().get_key().unwrap().unwrap();
+    assert!(FileStorage::new(&path).unwrap().get_secret().unwrap().is_none());
+    assert!(keystore.get_secret(&key).unwrap().is_some());
+    #[cfg(unix)]
+    assert_eq!(keystore.path(&key).metadata().unwrap().permissions().mode() & 0o777, 0o600);
+
+    HypercoreBuilder::new().keystore(&mut keystore).build(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(FileStorage::new(&path).unwrap().get_key().unwrap().unwrap(), key);
+}
+
+#[test]
+fn test_file_keystore_errors() {
+    let keys = temp_dir("dat-test-keystore-errors");
+    let keystore = FileKeyStore::new(&keys).unwrap();
+    let key = [7u8; 32];
+    assert!(keystore.get_secret(&key).unwrap().is_none());
+
+    // A secret that can't be read isn't the same as no secret.
+    create_dir_all(keystore.path(&key)).unwrap();
+    assert!(keystore.get_secret(&key).is_err());
+}
+
+#[test]
+fn test_memory_keystore() {
+    let mut keystore = MemoryKeyStore::new();
+    let mut feed = HypercoreBuilder::new().keystore(&mut keystore).build(MemoryStorage::new()).unwrap();
+    feed.append(b"secret".to_vec()).unwrap();
+    assert_eq!(feed.get(0).unwrap().unwrap(), b"secret".to_vec());
+}
+
+fn feed_files(path: &Path) -> Vec<Vec<u8>> {
+    let dir = path.join(".dat");
+    ["metadata.key", "metadata.tree", "metadata.signatures", "metadata.data"].iter()
+        .map(|name| read(dir.join(name)).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_from_seed_is_reproducible() {
+    let first = temp_dir("dat-test-seed-1");
+    let second = temp_dir("dat-test-seed-2");
+
+    for path in [&first, &second].iter() {
+        let mut feed = HypercoreBuilder::new().seed([42u8; 32]).build(FileStorage::new(path).unwrap()).unwrap();
+        for i in 0..8 {
+            feed.append(vec![i as u8; 100]).unwrap();
+        }
+    }
+
+    assert_eq!(feed_files(&first), feed_files(&second));
+
+    let other = temp_dir("dat-test-seed-3");
+    HypercoreBuilder::new().seed([7u8; 32]).build(FileStorage::new(&other).unwrap()).unwrap();
+    assert!(feed_files(&other)[0] != feed_files(&first)[0]);
+}
+
+#[test]
+fn test_with_rng_is_reproducible() {
+    let first = temp_dir("dat-test-rng-1");
+    let second = temp_dir("dat-test-rng-2");
+
+    for path in [&first, &second].iter() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut feed = HypercoreBuilder::new().rng(&mut rng).build(FileStorage::new(path).unwrap()).unwrap();
+        feed.append(b"hello".to_vec()).unwrap();
+    }
+
+    assert_eq!(feed_files(&first), feed_files(&second));
+}
+
+#[test]
+fn test_import_keypair() {
+    let path = temp_dir("dat-test-import");
+    let mut keystore = MemoryKeyStore::new();
+
+    let (key, secret) = {
+        let mut feed = HypercoreBuilder::new().keystore(&mut keystore).build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"first".to_vec()).unwrap();
+        feed.append(b"second".to_vec()).unwrap();
+        feed.export_keypair().unwrap()
+    };
+
+    let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(feed.key(), key);
+    assert!(!feed.writable());
+    assert!(feed.export_keypair().is_none());
+    assert!(feed.append(b"third".to_vec()).is_err());
+
+    let mut forged = secret;
+    forged[0] ^= 1;
+    assert!(feed.import_keypair(key, forged, None).is_err());
+
+    let other = HypercoreBuilder::new().seed([9u8; 32]).build(MemoryStorage::new()).unwrap();
+    let (other_key, other_secret) = other.export_keypair().unwrap();
+    assert!(feed.import_keypair(other_key, other_secret, None).is_err());
+    assert!(!feed.writable());
+
+    feed.import_keypair(key, secret, None).unwrap();
+    assert!(feed.writable());
+    feed.append(b"third".to_vec()).unwrap();
+    assert_eq!(feed.get(2).unwrap().unwrap(), b"third".to_vec());
+
+    let reopened = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(reopened.export_keypair(), Some((key, secret)));
+}
+
+#[test]
+fn test_import_keypair_checks_signatures() {
+    let path = temp_dir("dat-test-import-signatures");
+    let mut keystore = MemoryKeyStore::new();
+
+    let (key, secret) = {
+        let mut feed = HypercoreBuilder::new().keystore(&mut keystore).build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"data".to_vec()).unwrap();
+        feed.export_keypair().unwrap()
+    };
+
+    FileStorage::new(&path).unwrap().put_signature(1, vec![1u8; 64]).unwrap();
+
+    let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+    assert!(feed.import_keypair(key, secret, None).is_err());
+    assert!(!feed.writable());
+}
+
+#[test]
+fn test_sha256_tree() {
+    let path = temp_dir("dat-test-sha256");
+
+    {
+        let mut feed: Hypercore<FileStorage, Sha256> = HypercoreBuilder::with_hash().build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"compliant".to_vec()).unwrap();
+    }
+
+    let tree = read(path.join(".dat").join("metadata.tree")).unwrap();
+    assert_eq!(&tree[7..14], b"\x06SHA256");
+
+    assert!(Hypercore::new(FileStorage::new(&path).unwrap()).is_err());
+
+    let feed: Hypercore<FileStorage, Sha256> = HypercoreBuilder::with_hash().build(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(feed.get(0).unwrap().unwrap(), b"compliant".to_vec());
+}
+
+// The signed digest covers each root's hash, index and length.
+fn root_parts(node: &Node) -> Vec<Vec<u8>> {
+    let encode = |value: u64| (0..8).map(|i| (value >> (8 * i)) as u8).collect::<Vec<u8>>();
+    vec![vec![2], node.hash.to_vec(), encode(node.index), encode(node.length)]
+}
+
+#[test]
+fn test_sha256_signs_sha256_roots() {
+    let path = temp_dir("dat-test-sha256-roots");
+
+    let key = {
+        let mut feed: Hypercore<FileStorage, Sha256> = HypercoreBuilder::with_hash().build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"compliant".to_vec()).unwrap();
+        feed.key()
+    };
+
+    let storage = FileStorage::new(&path).unwrap();
+    let root = storage.get_node(0).unwrap().unwrap();
+    let signature = storage.next_signature(0).unwrap().unwrap();
+
+    let parts = root_parts(&root);
+    let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+    let sha256 = <Sha256 as TreeHash>::digest(&parts);
+    let blake2b = <Blake2b as TreeHash>::digest(&parts);
+    assert!(sha256 != blake2b);
+
+    let verifier = Ed25519Verifier::new(&key).unwrap();
+    assert!(verifier.verify(&sha256, &signature));
+    assert!(!verifier.verify(&blake2b, &signature));
+}
+
+// Stands in for a signer living outside the process.
+struct RemoteSigner {
+    inner:      Ed25519Signer,
+    signed:     Arc<AtomicUsize>,
+}
+
+struct RemoteVerifier {
+    inner:      Box<Verifier + Send + Sync>,
+}
+
+impl Signer for RemoteSigner {
+    fn scheme(&self) -> &str {
+        "Remote"
+    }
+
+    fn public_key(&self) -> [u8; 32] {
+        self.inner.public_key()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.signed.fetch_add(1, Ordering::SeqCst);
+        self.inner.sign(message)
+    }
+
+    fn verifier(&self) -> Box<Verifier + Send + Sync> {
+        Box::new(RemoteVerifier { inner: self.inner.verifier() })
+    }
+}
+
+impl Verifier for RemoteVerifier {
+    fn scheme(&self) -> &str {
+        "Remote"
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.inner.verify(message, signature)
+    }
+}
+
+fn remote_signer(seed: u8, signed: &Arc<AtomicUsize>) -> Box<Signer + Send + Sync> {
+    let (key, secret) = HypercoreBuilder::new().seed([seed; 32]).build(MemoryStorage::new()).unwrap().export_keypair().unwrap();
+    Box::new(RemoteSigner {
+        inner:      Ed25519Signer::new(&key, &secret).unwrap(),
+        signed:     signed.clone(),
+    })
+}
+
+#[test]
+fn test_with_signer() {
+    let path = temp_dir("dat-test-signer");
+    let signed = Arc::new(AtomicUsize::new(0));
+
+    {
+        let mut feed = HypercoreBuilder::new().signer(remote_signer(1, &signed)).build(FileStorage::new(&path).unwrap()).unwrap();
+        assert!(feed.writable());
+        assert!(feed.export_keypair().is_none());
+        for i in 0..3 {
+            feed.append(vec![i; 10]).unwrap();
+        }
+    }
+    assert_eq!(signed.load(Ordering::SeqCst), 3);
+
+    let signatures = read(path.join(".dat").join("metadata.signatures")).unwrap();
+    assert_eq!(&signatures[7..14], b"\x06Remote");
+
+    assert!(Hypercore::new(FileStorage::new(&path).unwrap()).is_err());
+    assert!(HypercoreBuilder::new().signer(remote_signer(2, &signed)).build(FileStorage::new(&path).unwrap()).is_err());
+
+    let mut feed = HypercoreBuilder::new().signer(remote_signer(1, &signed)).build(FileStorage::new(&path).unwrap()).unwrap();
+    feed.append(b"more".to_vec()).unwrap();
+    assert_eq!(signed.load(Ordering::SeqCst), 4);
+    assert_eq!(feed.get(3).unwrap().unwrap(), b"more".to_vec());
+}
+
+struct ShortSigner {
+    inner:      Ed25519Signer,
+}
+
+impl Signer for ShortSigner {
+    fn scheme(&self) -> &str {
+        "Short"
+    }
+
+    fn public_key(&self) -> [u8; 32] {
+        self.inner.public_key()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.inner.sign(message).unwrap()[..32].to_vec())
+    }
+
+    fn verifier(&self) -> Box<Verifier + Send + Sync> {
+        self.inner.verifier()
+    }
+}
+
+#[test]
+fn test_with_verifier() {
+    let path = temp_dir("dat-test-verifier");
+    let signed = Arc::new(AtomicUsize::new(0));
+    let signer = remote_signer(1, &signed);
+    let key = signer.public_key();
+
+    {
+        let mut feed = HypercoreBuilder::new().signer(signer).build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"signed".to_vec()).unwrap();
+    }
+
+    let verifier = Box::new(RemoteVerifier { inner: remote_signer(1, &signed).verifier() });
+    let mut feed = HypercoreBuilder::new().verifier(key, verifier).build(FileStorage::new(&path).unwrap()).unwrap();
+    assert!(!feed.writable());
+    assert_eq!(feed.key(), key);
+    assert_eq!(feed.get(0).unwrap().unwrap(), b"signed".to_vec());
+    assert!(feed.append(b"unsigned".to_vec()).is_err());
+
+    let verifier = Box::new(RemoteVerifier { inner: remote_signer(1, &signed).verifier() });
+    assert!(HypercoreBuilder::new().verifier([7; 32], verifier).build(FileStorage::new(&path).unwrap()).is_err());
+    assert!(HypercoreBuilder::new().verifier(key, Box::new(Ed25519Verifier::new(&key).unwrap())).build(FileStorage::new(&path).unwrap()).is_err());
+
+    let (key, secret) = HypercoreBuilder::new().seed([3; 32]).build(MemoryStorage::new()).unwrap().export_keypair().unwrap();
+    let short = Box::new(ShortSigner { inner: Ed25519Signer::new(&key, &secret).unwrap() });
+    let mut feed = HypercoreBuilder::new().signer(short).build(MemoryStorage::new()).unwrap();
+    assert!(feed.append(b"short".to_vec()).is_err());
+}
+
+#[test]
+fn test_bitfield_budget() {
+    let path = temp_dir("dat-test-bitfield-budget");
+
+    {
+        let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+        for i in 0..9000 {
+            feed.append(vec![(i % 256) as u8]).unwrap();
+        }
+    }
+
+    {
+        let mut feed = HypercoreBuilder::new().bitfield_budget(3328).build(FileStorage::new(&path).unwrap()).unwrap();
+        for i in 0..9000 {
+            assert_eq!(feed.get(i).unwrap().unwrap(), vec![(i % 256) as u8]);
+        }
+        feed.append(b"more".to_vec()).unwrap();
+        feed.clear(8500..8501).unwrap();
+    }
+
+    let feed = HypercoreBuilder::new().bitfield_budget(3328).build(CachedStorage::new(FileStorage::new(&path).unwrap())).unwrap();
+    assert_eq!(feed.get(9000).unwrap().unwrap(), b"more".to_vec());
+    assert_eq!(feed.contiguous_length().unwrap(), 8500);
+
+    assert!(HypercoreBuilder::new().bitfield_budget(3328).build(MemoryStorage::new()).is_err());
+}
+
+#[test]
+fn test_builder_combines_options() {
+    let path = temp_dir("dat-test-builder");
+    let mut keystore = MemoryKeyStore::new();
+
+    {
+        let mut feed: Hypercore<FileStorage, Sha256> = HypercoreBuilder::with_hash()
+            .keystore(&mut keystore)
+            .seed([5u8; 32])
+            .build(FileStorage::new(&path).unwrap()).unwrap();
+        feed.append(b"combined".to_vec()).unwrap();
+    }
+
+    let key = HypercoreBuilder::new().seed([5u8; 32]).build(MemoryStorage::new()).unwrap().key();
+    assert_eq!(FileStorage::new(&path).unwrap().get_key().unwrap().unwrap(), key);
+    assert!(FileStorage::new(&path).unwrap().get_secret().unwrap().is_none());
+
+    let builder = HypercoreBuilder::with_hash().keystore(&mut keystore).bitfield_budget(3328);
+    let feed: SharedHypercore<FileStorage, Sha256> = SharedHypercore::with_builder(FileStorage::new(&path).unwrap(), builder).unwrap();
+    feed.append(b"more".to_vec()).unwrap();
+    assert_eq!(feed.get(0).unwrap().unwrap(), b"combined".to_vec());
+    assert_eq!(feed.contiguous_length().unwrap(), 2);
+}
+
+// Writes a bitfield the way versions before the index could be trusted
+// left it: a version 0 header and index bytes that say every byte is full.
+fn write_legacy_bitfield(path: &Path) {
+    let file = path.join(".dat").join("metadata.bitfield");
+    let mut buf = read(&file).unwrap();
+    buf[4] = 0;
+    let pages = (buf.len() - 32) / 3328;
+    for page in 0..pages {
+        let start = 32 + page * 3328 + 3072;
+        for byte in &mut buf[start..(start + 256)] {
+            *byte = 255;
+        }
+    }
+    write(&file, &buf).unwrap();
+}
+
+#[test]
+fn test_paged_legacy_bitfield() {
+    let path = temp_dir("dat-test-legacy-bitfield");
+
+    {
+        let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+        for i in 0..9000 {
+            feed.append(vec![(i % 256) as u8]).unwrap();
+        }
+        feed.clear(4000..4010).unwrap();
+    }
+    write_legacy_bitfield(&path);
+
+    let feed = HypercoreBuilder::new().bitfield_budget(3328).build(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(feed.contiguous_length().unwrap(), 4000);
+    assert!(!feed.has(4005).unwrap());
+
+    let bitfield = read(path.join(".dat").join("metadata.bitfield")).unwrap();
+    assert_eq!(bitfield[4], 1);
+}
+
+#[test]
+fn test_contiguous_length() {
+    let path = temp_dir("dat-test-contiguous");
+
+    {
+        let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+        assert_eq!(feed.contiguous_length().unwrap(), 0);
+        for i in 0..20 {
+            feed.append(vec![i]).unwrap();
+        }
+        assert_eq!(feed.contiguous_length().unwrap(), 20);
+
+        feed.clear(5..8).unwrap();
+        assert_eq!(feed.contiguous_length().unwrap(), 5);
+        assert!(!feed.has(6).unwrap());
+        assert!(feed.get(6).is_err());
+
+        feed.clear(10..12).unwrap();
+        assert_eq!(feed.contiguous_length().unwrap(), 5);
+        feed.append(vec![20]).unwrap();
+        assert_eq!(feed.contiguous_length().unwrap(), 5);
+    }
+
+    let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(feed.contiguous_length().unwrap(), 5);
+    assert!(feed.has(20).unwrap());
+    feed.append(vec![21]).unwrap();
+    assert_eq!(feed.get(21).unwrap().unwrap(), vec![21]);
+}
+
+#[test]
+fn test_proof_with_digest() {
+    let mut feed = Hypercore::new(MemoryStorage::new()).unwrap();
+    for i in 0..16u8 {
+        feed.append(vec![i; 32]).unwrap();
+    }
+
+    // Download the feed in order, sending what we have with each request.
+    let mut local = Bitfield::new();
+    let mut full_nodes = 0;
+    let mut sent_nodes = 0;
+    for i in 0..16 {
+        let full = feed.proof(i, 0).unwrap();
+        let proof = feed.proof(i, local.digest(2 * i).unwrap()).unwrap();
+        assert!(full.signature.is_some());
+        assert!(proof.signature.is_some());
+
+        for node in &proof.nodes {
+            let same = full.nodes.iter().find(|other| other.index == node.index).unwrap();
+            assert_eq!(same.hash, node.hash);
+        }
+
+        full_nodes += full.nodes.len();
+        sent_nodes += proof.nodes.len();
+        local.set(i, true).unwrap();
+    }
+    assert!(sent_nodes * 2 <= full_nodes);
+
+    let proof = feed.proof(3, local.digest(6).unwrap()).unwrap();
+    assert!(proof.nodes.is_empty());
+    assert!(proof.signature.is_none());
+    assert!(feed.proof(16, 0).is_err());
+}
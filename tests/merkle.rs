@@ -1,9 +1,11 @@
 extern crate dat;
 extern crate blake2;
+extern crate sha2;
 
 use blake2::Blake2b;
+use sha2::Sha256;
 
-use dat::common::merkle::Tree;
+use dat::common::merkle::{Tree, TreeHash};
 
 #[test]
 fn test_one_root_on_power_of_two() {
@@ -41,4 +43,24 @@ fn test_can_handle_large_trees() {
 
     tree.insert::<Blake2b>(b"test".to_vec());
     assert!(tree.roots.len() > 1);    
+}
+
+#[test]
+fn test_hash_is_pluggable() {
+    let mut blake = Tree::new();
+    let mut sha = Tree::new();
+
+    for _ in 0..4 {
+        blake.insert::<Blake2b>(b"test".to_vec());
+        sha.insert::<Sha256>(b"test".to_vec());
+    }
+
+    assert_eq!(blake.roots.len(), sha.roots.len());
+    assert!(blake.roots[0].hash != sha.roots[0].hash);
+    assert_eq!(sha.roots[0].hash, {
+        let leaf = Sha256::digest(&[b"test"]);
+        let parent = Sha256::digest(&[&leaf, &leaf]);
+        Sha256::digest(&[&parent, &parent])
+    });
+    assert_eq!(Sha256::name(), "SHA256");
 }
\ No newline at end of file
@@ -0,0 +1,378 @@
+extern crate dat;
+extern crate futures;
+extern crate rand;
+
+mod common;
+
+use std::fs::remove_file;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use futures::Future;
+
+use dat::common::merkle::Node;
+use dat::core::Hypercore;
+use dat::core::storage::{Storage, AsyncStorage, FileType, FileHeader, FileStorage, MemoryStorage, PooledStorage, CachedStorage, CachedStorageBuilder, Layout};
+use dat::core::storage::{CompositeStorage, Backend, MappedFile, MmapStorage};
+use dat::core::storage::file::open_or_create;
+
+use common::temp_dir;
+
+struct CountingStorage<T: Storage> {
+    tree_reads:     Arc<AtomicUsize>,
+    storage:        T,
+}
+
+impl<T: Storage> Storage for CountingStorage<T> {
+    fn read_archive(&self, file_type: FileType, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let FileType::Tree = file_type {
+            self.tree_reads.fetch_add(1, Ordering::SeqCst);
+        }
+        self.storage.read_archive(file_type, offset, buf)
+    }
+
+    fn write_archive(&mut self, file_type: FileType, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.storage.write_archive(file_type, offset, buf)
+    }
+}
+
+fn put_block<T: Storage>(storage: &mut T, index: u64, data: Vec<u8>) {
+    storage.put_node(index, Node::with_hash(index, &[1u8; 32], data.len() as u64)).unwrap();
+    storage.put_data(index, data).unwrap();
+}
+
+#[test]
+fn test_header_round_trip() {
+    let header = FileHeader::new(FileType::Tree).unwrap();
+    let parsed = FileHeader::from_bytes(&header.to_bytes()).unwrap();
+
+    assert_eq!(parsed, header);
+    assert_eq!(parsed.algorithm, "BLAKE2b");
+}
+
+#[test]
+fn test_header_rejects_long_algorithm() {
+    let header = FileHeader::new(FileType::Tree).unwrap().with_algorithm(&"x".repeat(24)).unwrap();
+    assert_eq!(FileHeader::from_bytes(&header.to_bytes()).unwrap(), header);
+
+    assert!(FileHeader::new(FileType::Tree).unwrap().with_algorithm(&"x".repeat(25)).is_err());
+}
+
+#[test]
+fn test_setup_writes_headers() {
+    let mut storage = MemoryStorage::new();
+    storage.setup().unwrap();
+
+    let mut buf = [0u8; 32];
+    storage.read_archive(FileType::Signatures, 0, &mut buf).unwrap();
+
+    assert_eq!(FileHeader::from_bytes(&buf).unwrap(), FileHeader::new(FileType::Signatures).unwrap());
+}
+
+#[test]
+fn test_setup_rejects_mismatched_header() {
+    let mut storage = MemoryStorage::new();
+    let mut header = FileHeader::new(FileType::Tree).unwrap();
+    header.algorithm = "SHA256".to_string();
+    storage.write_archive(FileType::Tree, 0, &header.to_bytes()).unwrap();
+
+    assert!(storage.setup().is_err());
+
+    let mut buf = [0u8; 32];
+    storage.read_archive(FileType::Tree, 0, &mut buf).unwrap();
+    assert_eq!(FileHeader::from_bytes(&buf).unwrap(), header);
+}
+
+#[test]
+fn test_setup_migrates_legacy_tree_header() {
+    let mut storage = MemoryStorage::new();
+    let mut header = FileHeader::new(FileType::Tree).unwrap();
+    header.entry_size = 72;
+    storage.write_archive(FileType::Tree, 0, &header.to_bytes()).unwrap();
+
+    storage.setup().unwrap();
+
+    let mut buf = [0u8; 32];
+    storage.read_archive(FileType::Tree, 0, &mut buf).unwrap();
+    assert_eq!(FileHeader::from_bytes(&buf).unwrap().entry_size, 40);
+}
+
+#[test]
+fn test_setup_reindexes_legacy_bitfield() {
+    let mut storage = MemoryStorage::new();
+    let mut header = FileHeader::new(FileType::Bitfield).unwrap();
+    header.version = 0;
+    storage.write_archive(FileType::Bitfield, 0, &header.to_bytes()).unwrap();
+
+    let mut page = vec![0u8; 3328];
+    page[0] = 255;
+    for byte in &mut page[3072..] {
+        *byte = 255;
+    }
+    storage.write_archive(FileType::Bitfield, 32, &page).unwrap();
+
+    storage.setup().unwrap();
+
+    let mut buf = [0u8; 32];
+    storage.read_archive(FileType::Bitfield, 0, &mut buf).unwrap();
+    assert_eq!(FileHeader::from_bytes(&buf).unwrap(), FileHeader::new(FileType::Bitfield).unwrap());
+
+    let mut index = vec![0u8; 256];
+    storage.read_archive(FileType::Bitfield, 32 + 3072, &mut index).unwrap();
+    assert_eq!(index[0], 0b11000000);
+    assert_eq!(index[2], 0);
+}
+
+#[test]
+fn test_setup_rejects_garbage() {
+    let mut storage = MemoryStorage::new();
+    storage.write_archive(FileType::Bitfield, 0, b"not a header").unwrap();
+
+    assert!(storage.setup().is_err());
+}
+
+#[test]
+fn test_setup_rejects_unknown_version() {
+    let mut storage = MemoryStorage::new();
+    let mut header = FileHeader::new(FileType::Bitfield).unwrap();
+    header.version += 1;
+    storage.write_archive(FileType::Bitfield, 0, &header.to_bytes()).unwrap();
+
+    assert!(storage.setup().is_err());
+}
+
+#[test]
+fn test_concurrent_reads() {
+    let path = temp_dir("dat-test-concurrent");
+
+    let mut storage = FileStorage::new(&path).unwrap();
+    for i in 0..64u8 {
+        storage.write_archive(FileType::Data, i as u64 * 1024, &[i; 1024]).unwrap();
+    }
+
+    let storage = Arc::new(storage);
+    let handles: Vec<_> = (0..4).map(|_| {
+        let storage = Arc::clone(&storage);
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            for i in 0..64u8 {
+                assert_eq!(storage.read_archive(FileType::Data, i as u64 * 1024, &mut buf).unwrap(), 1024);
+                assert!(buf.iter().all(|&byte| byte == i));
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_pooled_storage() {
+    let storage = PooledStorage::new(MemoryStorage::new());
+
+    storage.write_archive(FileType::Data, 0, vec![1u8; 256]).wait().unwrap();
+    storage.write_archive(FileType::Data, 256, vec![2u8; 256]).wait().unwrap();
+
+    let data = storage.read_archive(FileType::Data, 128, 256).wait().unwrap();
+    assert_eq!(&data[..128], &[1u8; 128][..]);
+    assert_eq!(&data[128..], &[2u8; 128][..]);
+
+    let data = storage.read_archive(FileType::Data, 448, 256).wait().unwrap();
+    assert_eq!(data.len(), 64);
+}
+
+#[test]
+fn test_cached_data_blocks() {
+    let mut storage = CachedStorage::new(MemoryStorage::new());
+    put_block(&mut storage, 0, vec![1u8; 64]);
+    put_block(&mut storage, 2, vec![2u8; 64]);
+
+    for _ in 0..4 {
+        assert_eq!(storage.get_data(0).unwrap().unwrap(), vec![1u8; 64]);
+        assert_eq!(storage.get_data(2).unwrap().unwrap(), vec![2u8; 64]);
+    }
+    assert_eq!(storage.data_stats().hits, 8);
+    assert_eq!(storage.data_stats().misses, 0);
+
+    storage.write_archive(FileType::Data, 96, &[3u8; 8]).unwrap();
+    assert_eq!(storage.get_data(0).unwrap().unwrap(), vec![1u8; 64]);
+    assert_eq!(&storage.get_data(2).unwrap().unwrap()[32..40], &[3u8; 8]);
+    assert_eq!(storage.data_stats().misses, 1);
+}
+
+#[test]
+fn test_cached_empty_block() {
+    let mut storage = CachedStorage::new(MemoryStorage::new());
+    put_block(&mut storage, 0, Vec::new());
+    put_block(&mut storage, 2, vec![1u8; 64]);
+
+    // Both blocks start at byte 0.
+    assert_eq!(storage.get_data(2).unwrap().unwrap(), vec![1u8; 64]);
+    assert_eq!(storage.get_data(0).unwrap().unwrap(), Vec::<u8>::new());
+
+    storage.write_archive(FileType::Data, 0, &[3u8; 8]).unwrap();
+    assert_eq!(&storage.get_data(2).unwrap().unwrap()[..8], &[3u8; 8]);
+
+    storage.invalidate_data(0);
+    storage.write_archive(FileType::Data, 8, &[4u8; 8]).unwrap();
+    assert_eq!(&storage.get_data(2).unwrap().unwrap()[8..16], &[4u8; 8]);
+}
+
+#[test]
+fn test_cached_data_byte_budget() {
+    let mut storage = CachedStorageBuilder::new().data_bytes(100).build(MemoryStorage::new());
+    put_block(&mut storage, 0, vec![1u8; 64]);
+    put_block(&mut storage, 2, vec![2u8; 64]);
+
+    assert_eq!(storage.get_data(2).unwrap().unwrap(), vec![2u8; 64]);
+    assert_eq!(storage.get_data(0).unwrap().unwrap(), vec![1u8; 64]);
+    assert_eq!(storage.get_data(2).unwrap().unwrap(), vec![2u8; 64]);
+    assert_eq!(storage.data_stats().hits, 1);
+    assert_eq!(storage.data_stats().misses, 2);
+    assert_eq!(storage.data_stats().evictions, 3);
+}
+
+#[test]
+fn test_cached_nodes_write_through() {
+    let mut storage = CachedStorageBuilder::new().node_capacity(2).build(MemoryStorage::new());
+    put_block(&mut storage, 0, vec![1u8; 8]);
+    put_block(&mut storage, 2, vec![2u8; 8]);
+
+    assert_eq!(storage.get_node(2).unwrap().unwrap().length, 8);
+    assert_eq!(storage.node_stats().misses, 0);
+
+    put_block(&mut storage, 4, vec![3u8; 8]);
+    assert_eq!(storage.node_stats().evictions, 1);
+
+    let misses = storage.node_stats().misses;
+    storage.invalidate_node(4);
+    assert_eq!(storage.get_node(4).unwrap().unwrap().length, 8);
+    assert_eq!(storage.node_stats().misses, misses + 1);
+
+    storage.clear();
+    storage.get_data(4).unwrap();
+    assert_eq!(storage.data_stats().misses, 1);
+}
+
+#[test]
+fn test_offset_index_avoids_tree_reads() {
+    let tree_reads = Arc::new(AtomicUsize::new(0));
+    let storage = CountingStorage { tree_reads: Arc::clone(&tree_reads), storage: MemoryStorage::new() };
+    let mut feed = Hypercore::new(storage).unwrap();
+
+    for i in 0..300 {
+        feed.append(vec![i as u8; 1 + i % 7]).unwrap();
+    }
+
+    let before = tree_reads.load(Ordering::SeqCst);
+    for i in 0..300u64 {
+        assert_eq!(feed.get(i).unwrap().unwrap(), vec![i as u8; 1 + i as usize % 7]);
+    }
+    assert_eq!(tree_reads.load(Ordering::SeqCst), before);
+}
+
+#[test]
+fn test_offset_index_rebuilt_on_open() {
+    let path = temp_dir("dat-test-offsets");
+
+    {
+        let mut feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+        for i in 0..20 {
+            feed.append(vec![i as u8; 1 + i % 5]).unwrap();
+        }
+    }
+
+    remove_file(path.join(".dat").join("metadata.offsets")).unwrap();
+
+    let storage = FileStorage::new(&path).unwrap();
+    assert_eq!(storage.get_offset(2 * 19).unwrap(), Some((55, 5)));
+    drop(storage);
+
+    let feed = Hypercore::new(FileStorage::new(&path).unwrap()).unwrap();
+    assert_eq!(feed.get(19).unwrap().unwrap(), vec![19u8; 5]);
+    drop(feed);
+
+    let storage = FileStorage::new(&path).unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(storage.read_archive(FileType::Offsets, 8 * 19, &mut buf).unwrap(), 8);
+    assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 60]);
+}
+
+#[test]
+fn test_layout_side_by_side_feeds() {
+    let path = temp_dir("dat-test-layout");
+    let metadata = Layout::new(&path);
+    let content = Layout::new(&path).prefix("content.");
+
+    assert_eq!(metadata.path(FileType::Tree), path.join("metadata.tree"));
+    assert_eq!(content.path(FileType::Secret), path.join("content.secret_key"));
+
+    {
+        let mut metadata = Hypercore::new(FileStorage::with_layout(&metadata).unwrap()).unwrap();
+        let mut content = Hypercore::new(FileStorage::with_layout(&content).unwrap()).unwrap();
+        metadata.append(b"metadata".to_vec()).unwrap();
+        content.append(b"content".to_vec()).unwrap();
+    }
+
+    assert!(path.join("content.data").is_file());
+    assert!(!path.join(".dat").exists());
+
+    let content = Hypercore::new(FileStorage::with_layout(&content).unwrap()).unwrap();
+    assert_eq!(content.get(0).unwrap().unwrap(), b"content".to_vec());
+}
+
+#[test]
+fn test_composite_storage() {
+    let fast = Layout::new(&temp_dir("dat-test-composite-fast"));
+    let slow = Layout::new(&temp_dir("dat-test-composite-slow"));
+
+    {
+        let storage = CompositeStorage::new(|file_type| -> std::io::Result<Backend> {
+            match file_type {
+                FileType::Data      => Ok(Box::new(try!(open_or_create(&slow.path(file_type))))),
+                FileType::Tree      => Ok(Box::new(try!(MappedFile::open(try!(open_or_create(&fast.path(file_type))), file_type)))),
+                _                   => Ok(Box::new(try!(open_or_create(&fast.path(file_type))))),
+            }
+        }).unwrap().route(FileType::Secret, Box::new(Vec::new()));
+
+        let mut feed = Hypercore::new(storage).unwrap();
+        feed.append(b"composite".to_vec()).unwrap();
+        assert_eq!(feed.get(0).unwrap().unwrap(), b"composite".to_vec());
+    }
+
+    assert!(slow.path(FileType::Data).metadata().unwrap().len() > 0);
+    assert_eq!(fast.path(FileType::Data).exists(), false);
+    assert!(fast.path(FileType::Tree).metadata().unwrap().len() > 0);
+    assert_eq!(fast.path(FileType::Secret).metadata().unwrap().len(), 0);
+}
+
+#[test]
+fn test_mmap_length_survives_crash() {
+    let path = temp_dir("dat-test-mmap-crash");
+    let node = Node::with_hash(0, &[1u8; 32], 3);
+
+    {
+        let mut storage = MmapStorage::new(&path).unwrap();
+        storage.setup().unwrap();
+        storage.put_node(0, node.clone()).unwrap();
+        storage.put_signature(0, vec![7; 64]).unwrap();
+        // Skips the trim on drop, like a crash would.
+        mem::forget(storage);
+    }
+
+    let tree = path.join(".dat").join("metadata.tree");
+    assert!(tree.metadata().unwrap().len() > 32 + 40);
+
+    let storage = MmapStorage::new(&path).unwrap();
+    let mut buf = [0u8; 64];
+    assert_eq!(storage.read_archive(FileType::Tree, 32 + 40, &mut buf).unwrap(), 0);
+    assert_eq!(storage.read_archive(FileType::Signatures, 32 + 64, &mut buf).unwrap(), 0);
+    assert_eq!(storage.get_node(0).unwrap().unwrap().hash, node.hash);
+    assert_eq!(storage.get_signature(0).unwrap().unwrap(), vec![7; 64]);
+    drop(storage);
+
+    assert_eq!(tree.metadata().unwrap().len(), 32 + 40);
+}